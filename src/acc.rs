@@ -1,44 +1,127 @@
 //! Acc(ounting) - keep track of completeness/failure
 
-use std::{collections::HashSet, sync::Arc};
+use std::{collections::HashSet, path::PathBuf, sync::Arc, time::Duration};
 
 use futures::lock::Mutex;
-use tokio::sync::mpsc;
+use tokio::{
+    fs,
+    io::{self, AsyncWriteExt},
+    sync::mpsc,
+};
 use tracing::{error, info, warn};
 
+use crate::dryrun;
+
 /// Set of Google MediaItem IDs to process
 pub struct _ToProcess {
     ids: Arc<Mutex<HashSet<String>>>,
 }
 
+/// Outcome of a single download, as recorded in the manifest
+#[derive(Clone, Default)]
+pub struct Transfer {
+    pub completed: bool,
+    pub retries: u32,
+    pub offset: u64,
+    pub throttled: bool,
+    pub sha256: Option<String>,
+}
+
+/// Outcome of a whole run, for callers to act on (rather than parsing logs)
+#[derive(Debug, Default)]
+pub struct PullReport {
+    // Handed to writers
+    pub listed: u64,
+    // Downloads started
+    pub total: u32,
+    pub completed: u32,
+    // Written before (when continuing), beyond the quota or with no room left
+    pub skipped: u32,
+    // Path and reason
+    pub failed: Vec<(String, String)>,
+    pub retries: u32,
+    // Written by completed downloads
+    pub bytes: u64,
+    pub wasted_bytes: u64,
+    pub duration: Duration,
+}
+
+impl PullReport {
+    /// One line of `key=value` pairs, for scripts
+    pub fn machine_summary(&self) -> String {
+        format!(
+            "total={} completed={} failed={} retries={} wasted_bytes={} \
+            listed={} skipped={} bytes={} duration_secs={:.3}",
+            self.total,
+            self.completed,
+            self.failed.len(),
+            self.retries,
+            self.wasted_bytes,
+            self.listed,
+            self.skipped,
+            self.bytes,
+            self.duration.as_secs_f64()
+        )
+    }
+}
+
 /// Events to track
 #[derive(Clone)]
 pub enum Event {
     New,
+    Skipped,
     Retrying(String, u8),
     RetryAfter(String, u64),
-    Failed(String),
+    // Path, reason
+    Failed(String, &'static str),
     FailedHttp(String, String),
-    Completed,
+    // ID, product URL, path written
+    Completed(String, String, PathBuf),
+    // ID, path written, outcome
+    Transferred(String, PathBuf, Transfer),
     Summarize,
 }
 
-/// Count and log
-pub async fn track_events(mut events: mpsc::Receiver<Event>) -> tokio::task::JoinHandle<()> {
-    /// Internal details
-    #[derive(Default)]
-    struct Tracker {
-        _total: i32,
-        _completed: i32,
-        _failed: i32,
+/// Open for appending, so continued pulls keep what was recorded before
+async fn open_append(
+    path: Option<PathBuf>,
+    header: &[u8],
+) -> anyhow::Result<Option<io::BufWriter<fs::File>>> {
+    let Some(path) = path else {
+        return Ok(None);
+    };
+
+    let is_new = fs::metadata(&path).await.is_err();
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    let mut out = io::BufWriter::new(file);
+    if is_new {
+        out.write_all(header).await?;
     }
 
-    tokio::spawn(async move {
-        let mut mem = Tracker::default();
+    Ok(Some(out))
+}
+
+/// Count and log into a report, optionally listing verified downloads (to archive them
+/// in the cloud) and per item outcomes (manifest, as JSON lines)
+pub async fn track_events(
+    mut events: mpsc::Receiver<Event>,
+    verified_list: Option<PathBuf>,
+    manifest: Option<PathBuf>,
+) -> anyhow::Result<tokio::task::JoinHandle<PullReport>> {
+    let mut verified = open_append(verified_list, b"id,product_url,path\r\n").await?;
+    let mut manifest = open_append(manifest, b"").await?;
+
+    Ok(tokio::spawn(async move {
+        let mut mem = PullReport::default();
 
         while let Some(event) = events.recv().await {
             match event {
-                Event::New => mem._total += 1,
+                Event::New => mem.total += 1,
+                Event::Skipped => mem.skipped += 1,
                 Event::Retrying(file, count) => warn!("Retried {file} {count} times ..."),
                 Event::RetryAfter(to_shorten, t_msec) => {
                     let mut url = to_shorten;
@@ -46,24 +129,83 @@ pub async fn track_events(mut events: mpsc::Receiver<Event>) -> tokio::task::Joi
                     let pause: f32 = t_msec as f32 / 1000f32;
                     warn!("GET {url}...timeout, retry in {pause:2.2}s")
                 }
-                Event::Failed(file) => {
-                    mem._failed += 1;
-                    error!("Givin' up on {file} ...")
+                Event::Failed(file, reason) => {
+                    error!("Givin' up on {file} ({reason}) ...");
+                    mem.failed.push((file, reason.to_string()));
                 }
                 Event::FailedHttp(file, code) => {
-                    mem._failed += 1;
-                    error!("HTTP {code} on {file}")
+                    error!("HTTP {code} on {file}");
+                    mem.failed.push((file, format!("HTTP {code}")));
+                }
+                Event::Completed(id, product_url, path) => {
+                    mem.completed += 1;
+                    if let Some(out) = verified.as_mut() {
+                        // (Text records can't hold non-UTF-8 paths, those get replacement chars.)
+                        let line = format!(
+                            "{id},{product_url},{}\r\n",
+                            dryrun::quote(&path.to_string_lossy())
+                        );
+                        if let Err(e) = out.write_all(line.as_bytes()).await {
+                            error!("Failed to list {path:?} as verified: {e}");
+                        }
+                    }
+                }
+                Event::Transferred(id, path, transfer) => {
+                    // Without Range resume, bytes of incomplete transfers are lost
+                    let wasted = if transfer.completed {
+                        0
+                    } else {
+                        transfer.offset
+                    };
+                    mem.retries += transfer.retries;
+                    mem.wasted_bytes += wasted;
+                    if transfer.completed {
+                        mem.bytes += transfer.offset;
+                    }
+                    if let Some(out) = manifest.as_mut() {
+                        let record = serde_json::json!({
+                            "id": id,
+                            "path": path.to_string_lossy(),
+                            "completed": transfer.completed,
+                            "retries": transfer.retries,
+                            "offset": transfer.offset,
+                            "wasted_bytes": wasted,
+                            "sha256": transfer.sha256,
+                        });
+                        if let Err(e) = out.write_all((record.to_string() + "\n").as_bytes()).await
+                        {
+                            error!("Failed to record {path:?} in manifest: {e}");
+                        }
+                    }
                 }
-                Event::Completed => mem._completed += 1,
                 Event::Summarize => {
-                    // assert_eq!(mem._total, mem._completed + mem._failed);
+                    // assert_eq!(mem.total, mem.completed + mem.failed.len());
                     info!(
-                        "Processed: total {}, completed {}, failed {}",
-                        mem._total, mem._completed, mem._failed
+                        "Processed: total {}, completed {}, skipped {}, failed {}, retries {}, \
+                        wasted {} bytes",
+                        mem.total,
+                        mem.completed,
+                        mem.skipped,
+                        mem.failed.len(),
+                        mem.retries,
+                        mem.wasted_bytes
                     );
                     break;
                 }
             }
         }
-    })
+
+        if let Some(mut out) = verified {
+            if let Err(e) = out.flush().await {
+                error!("Failed to write list of verified downloads: {e}");
+            }
+        }
+        if let Some(mut out) = manifest {
+            if let Err(e) = out.flush().await {
+                error!("Failed to write manifest: {e}");
+            }
+        }
+
+        mem
+    }))
 }
@@ -0,0 +1,184 @@
+//! Auth(entication) and client setup
+
+use std::{path::PathBuf, str::FromStr};
+
+use anyhow::{bail, Context};
+use google_photoslibrary1 as photoslibrary1;
+use photoslibrary1::{
+    hyper::{self, client::HttpConnector},
+    hyper_rustls,
+    oauth2::{
+        self, authenticator::Authenticator, authorized_user::AuthorizedUserSecret,
+        ApplicationSecret,
+    },
+    PhotosLibrary,
+};
+use tokio::fs;
+use tracing::info;
+
+use crate::{config, dns};
+
+/// Where OAuth tokens come from
+#[derive(Clone, Debug, Default)]
+pub enum Backend {
+    /// Browser based installed flow, tokens persisted to the token store
+    #[default]
+    Installed,
+    /// Refresh token provisioned elsewhere, read from a file (for automation)
+    RefreshToken(PathBuf),
+    /// Token of an rclone remote, which must be configured with its own client ID
+    Rclone(String),
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "installed" => Ok(Backend::Installed),
+            Some(("refresh-token", path)) if !path.is_empty() => {
+                Ok(Backend::RefreshToken(PathBuf::from(path)))
+            }
+            Some(("rclone", remote)) if !remote.is_empty() => {
+                Ok(Backend::Rclone(remote.to_string()))
+            }
+            _ => Err(format!(
+                "unknown auth '{s}', expected installed, refresh-token:FILE or rclone:REMOTE"
+            )),
+        }
+    }
+}
+
+/// Build HTTP client and an authenticated hub, running the installed flow if required
+pub async fn connect(
+    client_secret: Option<PathBuf>,
+    backend: Backend,
+    resolver: dns::Resolver,
+) -> anyhow::Result<(hyper::Client<dns::Connector>, PhotosLibrary<dns::Connector>)> {
+    // Client setup
+    let mut http = HttpConnector::new_with_resolver(resolver);
+    http.enforce_http(false); // (Required when wrapped, `https_only` still applies.)
+    let client = hyper::Client::builder().build(
+        hyper_rustls::HttpsConnectorBuilder::new()
+            .with_native_roots()
+            .https_only()
+            .enable_http1()
+            .enable_http2()
+            .wrap_connector(http),
+    );
+
+    // Auth
+    let auth = match backend {
+        Backend::Installed => {
+            let store = config::get_token_store_path()?;
+            let auth = oauth2::InstalledFlowAuthenticator::builder(
+                app_secret(client_secret).await?,
+                oauth2::InstalledFlowReturnMethod::HTTPRedirect,
+            )
+            .hyper_client(client.clone())
+            .persist_tokens_to_disk(&store)
+            .build()
+            .await?;
+            info!("Tokens stored to {store:?}");
+            auth
+        }
+        Backend::RefreshToken(path) => {
+            let secret = app_secret(client_secret).await?;
+            let refresh_token = fs::read_to_string(&path)
+                .await
+                .with_context(|| format!("Failed to read refresh token from {path:?}"))?;
+            authorized_user(
+                &client,
+                secret.client_id,
+                secret.client_secret,
+                refresh_token.trim().to_string(),
+            )
+            .await?
+        }
+        Backend::Rclone(remote) => {
+            let (client_id, secret, refresh_token) = rclone_token(&remote).await?;
+            info!("Using tokens of rclone remote '{remote}'");
+            authorized_user(&client, client_id, secret, refresh_token).await?
+        }
+    };
+
+    let hub = PhotosLibrary::new(client.clone(), auth);
+
+    Ok((client, hub))
+}
+
+/// Get app secret from the client secret file
+///  (rclone remotes bring their own, from rclone's config file.)
+async fn app_secret(client_secret: Option<PathBuf>) -> anyhow::Result<ApplicationSecret> {
+    let path =
+        client_secret.context("--client-secret is required, unless using --auth rclone:REMOTE")?;
+    config::get_app_secret(path).await
+}
+
+/// Authenticator refreshing access tokens from a refresh token (kept in memory only)
+async fn authorized_user(
+    client: &hyper::Client<dns::Connector>,
+    client_id: String,
+    client_secret: String,
+    refresh_token: String,
+) -> anyhow::Result<Authenticator<dns::Connector>> {
+    let secret = AuthorizedUserSecret {
+        client_id,
+        client_secret,
+        refresh_token,
+        key_type: "authorized_user".to_string(),
+    };
+
+    Ok(
+        oauth2::AuthorizedUserAuthenticator::with_client(secret, client.clone())
+            .build()
+            .await?,
+    )
+}
+
+/// Client ID, client secret and refresh token of a Google Photos remote in rclone's config
+pub async fn rclone_token(remote: &str) -> anyhow::Result<(String, String, String)> {
+    let path = config::get_rclone_config_path()?;
+    let content = fs::read_to_string(&path)
+        .await
+        .with_context(|| format!("Failed to read rclone config {path:?}"))?;
+    if content.starts_with("# Encrypted rclone configuration") {
+        bail!("rclone config {path:?} is encrypted, decrypt it or use another auth");
+    }
+
+    // Keys of the remote's section
+    let header = format!("[{remote}]");
+    let settings: Vec<(&str, &str)> = content
+        .lines()
+        .map(str::trim)
+        .skip_while(|line| *line != header)
+        .skip(1)
+        .take_while(|line| !line.starts_with('['))
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.trim(), value.trim()))
+        .collect();
+    let setting = |key: &str| {
+        settings
+            .iter()
+            .find(|(k, _)| *k == key)
+            .map(|(_, value)| value.to_string())
+    };
+
+    let kind = setting("type").with_context(|| format!("No remote '{remote}' in {path:?}"))?;
+    if kind != "google photos" {
+        bail!("rclone remote '{remote}' is of type '{kind}', expected 'google photos'");
+    }
+    // (Refresh tokens only work with the client ID that obtained them.)
+    let (Some(client_id), Some(client_secret)) = (setting("client_id"), setting("client_secret"))
+    else {
+        bail!("rclone remote '{remote}' uses rclone's built-in client ID, configure your own");
+    };
+    let token: serde_json::Value = serde_json::from_str(
+        &setting("token").with_context(|| format!("rclone remote '{remote}' has no token"))?,
+    )?;
+    let refresh_token = token["refresh_token"]
+        .as_str()
+        .with_context(|| format!("rclone remote '{remote}' has no refresh token"))?;
+
+    Ok((client_id, client_secret, refresh_token.to_string()))
+}
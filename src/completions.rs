@@ -0,0 +1,199 @@
+//! Shell completion scripts, generated from the argument definitions
+
+use std::str::FromStr;
+
+use argh::{ArgsInfo, CommandInfoWithArgs, FlagInfo, FlagInfoKind};
+
+use crate::config::Cmdlargs;
+
+/// Shells we can complete for
+#[derive(Clone, Copy, Debug)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+impl FromStr for Shell {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bash" => Ok(Shell::Bash),
+            "zsh" => Ok(Shell::Zsh),
+            "fish" => Ok(Shell::Fish),
+            other => Err(format!(
+                "unknown shell '{other}', expected bash, zsh or fish"
+            )),
+        }
+    }
+}
+
+/// Completion script for `shell`
+pub fn script(shell: Shell) -> String {
+    let info = Cmdlargs::get_args_info();
+    match shell {
+        Shell::Bash => bash(&info),
+        Shell::Zsh => zsh(&info),
+        Shell::Fish => fish(&info),
+    }
+}
+
+/// Flags as typed on the command line, long and short
+fn spellings(flag: &FlagInfo) -> Vec<String> {
+    let mut words = vec![flag.long.to_string()];
+    if let Some(short) = flag.short {
+        words.push(format!("-{short}"));
+    }
+    words
+}
+
+fn takes_value(flag: &FlagInfo) -> bool {
+    matches!(flag.kind, FlagInfoKind::Option { .. })
+}
+
+fn bash(info: &CommandInfoWithArgs) -> String {
+    let top: Vec<String> = info
+        .flags
+        .iter()
+        .flat_map(spellings)
+        .chain(info.commands.iter().map(|c| c.name.to_string()))
+        .collect();
+    let names: Vec<&str> = info.commands.iter().map(|c| c.name).collect();
+
+    let mut cases = String::new();
+    for sub in &info.commands {
+        let words: Vec<String> = sub.command.flags.iter().flat_map(spellings).collect();
+        cases += &format!("        {}) opts=\"{}\" ;;\n", sub.name, words.join(" "));
+    }
+
+    format!(
+        r#"# bash completion for goopho
+_goopho() {{
+    local cur="${{COMP_WORDS[COMP_CWORD]}}"
+    local cmd="" opts=""
+    for word in "${{COMP_WORDS[@]:1:COMP_CWORD-1}}"; do
+        case "$word" in
+            {names}) cmd="$word" ;;
+        esac
+    done
+    case "$cmd" in
+{cases}        *) opts="{top}" ;;
+    esac
+    COMPREPLY=($(compgen -W "$opts" -- "$cur"))
+}}
+complete -o default -F _goopho goopho
+"#,
+        names = names.join("|"),
+        top = top.join(" "),
+    )
+}
+
+/// Escape for zsh `_arguments` specs inside single quotes
+fn zsh_escape(text: &str) -> String {
+    text.replace('\'', "'\\''")
+        .replace('[', "\\[")
+        .replace(']', "\\]")
+        .replace(':', "\\:")
+}
+
+fn zsh_specs(flags: &[FlagInfo]) -> Vec<String> {
+    flags
+        .iter()
+        .flat_map(|flag| {
+            let value = if takes_value(flag) {
+                format!(":{}:_files", flag.long.trim_start_matches('-'))
+            } else {
+                String::new()
+            };
+            let description = zsh_escape(flag.description);
+            spellings(flag)
+                .into_iter()
+                .map(move |word| format!("'{word}[{description}]{value}'"))
+        })
+        .collect()
+}
+
+fn zsh(info: &CommandInfoWithArgs) -> String {
+    let mut cases = String::new();
+    for sub in &info.commands {
+        let mut specs = zsh_specs(sub.command.flags);
+        specs.push("'*:file:_files'".to_string());
+        cases += &format!(
+            "                {})\n                    _arguments \\\n                        {}\n                    ;;\n",
+            sub.name,
+            specs.join(" \\\n                        ")
+        );
+    }
+    let commands: Vec<String> = info
+        .commands
+        .iter()
+        .map(|c| format!("'{}[{}]'", c.name, zsh_escape(c.command.description)))
+        .collect();
+    let mut top = zsh_specs(info.flags);
+    top.push("'1: :->command'".to_string());
+    top.push("'*:: :->args'".to_string());
+
+    format!(
+        r#"#compdef goopho
+
+_goopho() {{
+    local state line
+    _arguments -C \
+        {top}
+
+    case $state in
+        command)
+            _values 'command' {commands}
+            ;;
+        args)
+            case $line[1] in
+{cases}            esac
+            ;;
+    esac
+}}
+
+_goopho "$@"
+"#,
+        top = top.join(" \\\n        "),
+        commands = commands.join(" "),
+    )
+}
+
+/// Escape for fish single quoted strings
+fn fish_escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('\'', "\\'")
+}
+
+fn fish_line(condition: &str, flag: &FlagInfo) -> String {
+    let mut line = format!(
+        "complete -c goopho -n '{condition}' -l {}",
+        flag.long.trim_start_matches('-')
+    );
+    if let Some(short) = flag.short {
+        line += &format!(" -s {short}");
+    }
+    if takes_value(flag) {
+        line += " -r";
+    }
+    line + &format!(" -d '{}'\n", fish_escape(flag.description))
+}
+
+fn fish(info: &CommandInfoWithArgs) -> String {
+    let mut script = String::from("# fish completion for goopho\n");
+    for flag in info.flags {
+        script += &fish_line("__fish_use_subcommand", flag);
+    }
+    for sub in &info.commands {
+        script += &format!(
+            "complete -c goopho -n '__fish_use_subcommand' -f -a {} -d '{}'\n",
+            sub.name,
+            fish_escape(sub.command.description)
+        );
+        let condition = format!("__fish_seen_subcommand_from {}", sub.name);
+        for flag in sub.command.flags {
+            script += &fish_line(&condition, flag);
+        }
+    }
+    script
+}
@@ -1,50 +1,584 @@
 //! Abstraction over configuration details
 
-use std::{ffi::OsString, path::PathBuf};
+use std::{ffi::OsString, net::IpAddr, path::PathBuf};
 
 use anyhow::{bail, Context};
-use argh::FromArgs;
+use argh::{ArgsInfo, FromArgs};
 use directories::BaseDirs;
 use google_photoslibrary1 as photoslibrary1;
 use photoslibrary1::{
     chrono::NaiveDate,
+    hyper::{
+        header::{HeaderName, HeaderValue, USER_AGENT},
+        HeaderMap,
+    },
     oauth2::{ApplicationSecret, ConsoleApplicationSecret},
 };
 use tokio::fs;
+use tracing_subscriber::EnvFilter;
+
+use crate::{auth, completions, dns, dryrun};
 
 /// This app's command line args
-#[derive(FromArgs, Debug)]
+#[derive(FromArgs, ArgsInfo, Debug)]
 /// Download images and videos from Google Photos
 pub struct Cmdlargs {
+    /// errors only, plus a machine readable summary on stdout (unless a dry run lists there)
+    #[argh(switch, short = 'q')]
+    pub quiet: bool,
+
+    /// debug output of goopho itself (overrides RUST_LOG)
+    #[argh(switch, short = 'v')]
+    pub verbose: bool,
+
+    /// don't show progress indicators (implied when stderr is no terminal)
+    #[argh(switch)]
+    pub no_progress: bool,
+
+    #[argh(subcommand)]
+    pub command: Command,
+}
+
+impl Cmdlargs {
+    /// Filter traces by flags, falling back to RUST_LOG and then to info
+    pub fn log_filter(&self) -> anyhow::Result<EnvFilter> {
+        Ok(match (self.quiet, self.verbose) {
+            (true, true) => bail!("--quiet and --verbose contradict each other, pick one"),
+            (true, false) => EnvFilter::new("error"),
+            // (Debug output of hyper, h2 and rustls would bury goopho's own.)
+            (false, true) => EnvFilter::new("info,goopho=debug"),
+            (false, false) => {
+                EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"))
+            }
+        })
+    }
+}
+
+/// Subcommands
+#[derive(FromArgs, ArgsInfo, Debug)]
+#[argh(subcommand)]
+#[allow(clippy::large_enum_variant)] // Parsed once, boxing buys nothing
+pub enum Command {
+    Pull(PullArgs),
+    Plan(PlanArgs),
+    Apply(ApplyArgs),
+    Doctor(DoctorArgs),
+    Completions(CompletionsArgs),
+}
+
+#[derive(FromArgs, ArgsInfo, Debug)]
+#[argh(
+    subcommand,
+    name = "pull",
+    example = "Pull everything taken since 2020:\n$ {command_name} -c client_secret.json -f 2020 photos",
+    example = "List what a pull would write, with IDs and types:\n$ {command_name} -c client_secret.json -d --columns path,id,mime photos",
+    example = "Continue an interrupted pull, recording verified downloads:\n$ {command_name} -c client_secret.json --continue --verified-list done.csv photos",
+    example = "Pull with the token of an rclone remote (configured with your own client ID):\n$ {command_name} --auth rclone:gphotos photos"
+)]
+/// Download media files into a new target folder
+pub struct PullArgs {
     /// just show what would be written
     #[argh(switch, short = 'd')]
     pub dry_run: bool,
 
+    /// write dry-run listing to this CSV file (default stdout)
+    #[argh(option, short = 'o')]
+    pub output: Option<PathBuf>,
+
+    /// dry-run columns, comma separated (creation_time, path, id, mime, width, height)
+    #[argh(option, default = "dryrun::Columns::default()")]
+    pub columns: dryrun::Columns,
+
     /* Not sure if we ever want to implement this?
      *
     /// persist creation date from Google Photos to disk
     #[argh(switch, long = "pcd")]
     pub persist_creation_date: bool,
      */
-    /// don't select media files created earlier (year-month-day)
-    #[argh(option, short = 'f')]
+    /// don't select media files created earlier (YYYY-MM-DD, YYYY/MM/DD, YYYYMMDD or YYYY)
+    #[argh(option, short = 'f', from_str_fn(parse_from_date))]
     pub from_date: Option<NaiveDate>,
 
-    /// don't select media files created later
-    #[argh(option, short = 't')]
+    /// don't select media files created later (a bare year means end of year)
+    #[argh(option, short = 't', from_str_fn(parse_to_date))]
     pub to_date: Option<NaiveDate>,
 
     /// path to client secret file (the one you got from Google)
     #[argh(option, short = 'c')]
-    pub client_secret: PathBuf,
+    pub client_secret: Option<PathBuf>,
+
+    /// where tokens come from: installed (browser flow, default), refresh-token:FILE or rclone:REMOTE
+    #[argh(option, default = "auth::Backend::default()")]
+    pub auth: auth::Backend,
 
-    /// target folder (must *not* exist)
+    /// target folder (must *not* exist, unless continuing)
     #[argh(positional)]
     pub target: PathBuf,
 
+    /// stop writing to the target folder once this many bytes are in (e.g. 2TB)
+    #[argh(option, from_str_fn(parse_bytes))]
+    pub capacity: Option<u64>,
+
+    /// folder to spill over to when earlier ones are full ("folder=capacity", repeatable)
+    #[argh(option, from_str_fn(parse_spill))]
+    pub spill: Vec<(PathBuf, u64)>,
+
+    /// continue an interrupted pull, listing from where it left off
+    #[argh(switch, long = "continue")]
+    pub resume: bool,
+
+    /// list IDs and product URLs of verified downloads to this CSV file (for archiving)
+    #[argh(option)]
+    pub verified_list: Option<PathBuf>,
+
+    /// record retries, offsets and wasted bytes per item to this JSON lines file
+    #[argh(option, short = 'm')]
+    pub manifest: Option<PathBuf>,
+
+    /// hash downloads while writing and record SHA-256 digests in the manifest
+    #[argh(switch)]
+    pub sha256: bool,
+
     /// don't force processing to be FIFO
     #[argh(switch, short = 'u')]
     pub unordered: bool,
+
+    /// maximum number of concurrent downloads
+    #[argh(option, default = "100")]
+    pub concurrency: usize,
+
+    /// adapt concurrency to timeouts and throttling (up to --concurrency)
+    #[argh(switch)]
+    pub adaptive: bool,
+
+    /// stop the run after this many retries across all downloads (default unlimited)
+    #[argh(option)]
+    pub retry_budget: Option<u32>,
+
+    /// end the run once this many files are written
+    #[argh(option)]
+    pub stop_after_files: Option<u64>,
+
+    /// end the run once this many bytes are written, downloads in flight still finish (e.g. 25GB, 4GiB)
+    #[argh(option, from_str_fn(parse_bytes))]
+    pub stop_after_bytes: Option<u64>,
+
+    /// user agent to send with downloads
+    #[argh(option, from_str_fn(parse_header_value))]
+    pub user_agent: Option<HeaderValue>,
+
+    /// extra header to send with downloads ("Name: value", repeatable)
+    #[argh(option, short = 'H', from_str_fn(parse_header))]
+    pub header: Vec<(HeaderName, HeaderValue)>,
+
+    /// only connect via IP version 4 or 6
+    #[argh(option)]
+    pub ip_version: Option<dns::IpVersion>,
+
+    /// resolve host to address instead of asking DNS ("host=address", repeatable)
+    #[argh(option, from_str_fn(dns::parse_override))]
+    pub resolve: Vec<(String, IpAddr)>,
+
+    /// capacity of each file's write buffer in KiB (larger suits NAS targets)
+    #[argh(option, default = "8")]
+    pub write_buffer: usize,
+}
+
+#[derive(FromArgs, ArgsInfo, Debug)]
+#[argh(
+    subcommand,
+    name = "plan",
+    example = "Plan a pull of 2023, to review and trim before applying:\n$ {command_name} -c client_secret.json -f 2023 -t 2023 -o plan.json photos"
+)]
+/// List media files a pull would write into an editable JSON plan
+pub struct PlanArgs {
+    /// don't select media files created earlier (YYYY-MM-DD, YYYY/MM/DD, YYYYMMDD or YYYY)
+    #[argh(option, short = 'f', from_str_fn(parse_from_date))]
+    pub from_date: Option<NaiveDate>,
+
+    /// don't select media files created later (a bare year means end of year)
+    #[argh(option, short = 't', from_str_fn(parse_to_date))]
+    pub to_date: Option<NaiveDate>,
+
+    /// path to client secret file (the one you got from Google)
+    #[argh(option, short = 'c')]
+    pub client_secret: Option<PathBuf>,
+
+    /// where tokens come from: installed (browser flow, default), refresh-token:FILE or rclone:REMOTE
+    #[argh(option, default = "auth::Backend::default()")]
+    pub auth: auth::Backend,
+
+    /// write the plan to this JSON file
+    #[argh(option, short = 'o')]
+    pub output: PathBuf,
+
+    /// target folder to apply the plan to (must *not* exist yet)
+    #[argh(positional)]
+    pub target: PathBuf,
+
+    /// only connect via IP version 4 or 6
+    #[argh(option)]
+    pub ip_version: Option<dns::IpVersion>,
+
+    /// resolve host to address instead of asking DNS ("host=address", repeatable)
+    #[argh(option, from_str_fn(dns::parse_override))]
+    pub resolve: Vec<(String, IpAddr)>,
+}
+
+#[derive(FromArgs, ArgsInfo, Debug)]
+#[argh(
+    subcommand,
+    name = "apply",
+    example = "Download what is left in a reviewed plan:\n$ {command_name} -c client_secret.json plan.json"
+)]
+/// Download the media files of a plan (again, to finish an interrupted apply)
+pub struct ApplyArgs {
+    /// path to client secret file (the one you got from Google)
+    #[argh(option, short = 'c')]
+    pub client_secret: Option<PathBuf>,
+
+    /// where tokens come from: installed (browser flow, default), refresh-token:FILE or rclone:REMOTE
+    #[argh(option, default = "auth::Backend::default()")]
+    pub auth: auth::Backend,
+
+    /// plan file written by the plan subcommand
+    #[argh(positional)]
+    pub plan: PathBuf,
+
+    /// list IDs and product URLs of verified downloads to this CSV file (for archiving)
+    #[argh(option)]
+    pub verified_list: Option<PathBuf>,
+
+    /// record retries, offsets and wasted bytes per item to this JSON lines file
+    #[argh(option, short = 'm')]
+    pub manifest: Option<PathBuf>,
+
+    /// hash downloads while writing and record SHA-256 digests in the manifest
+    #[argh(switch)]
+    pub sha256: bool,
+
+    /// maximum number of concurrent downloads
+    #[argh(option, default = "100")]
+    pub concurrency: usize,
+
+    /// adapt concurrency to timeouts and throttling (up to --concurrency)
+    #[argh(switch)]
+    pub adaptive: bool,
+
+    /// stop the run after this many retries across all downloads (default unlimited)
+    #[argh(option)]
+    pub retry_budget: Option<u32>,
+
+    /// end the run once this many files are written
+    #[argh(option)]
+    pub stop_after_files: Option<u64>,
+
+    /// end the run once this many bytes are written, downloads in flight still finish (e.g. 25GB, 4GiB)
+    #[argh(option, from_str_fn(parse_bytes))]
+    pub stop_after_bytes: Option<u64>,
+
+    /// user agent to send with downloads
+    #[argh(option, from_str_fn(parse_header_value))]
+    pub user_agent: Option<HeaderValue>,
+
+    /// extra header to send with downloads ("Name: value", repeatable)
+    #[argh(option, short = 'H', from_str_fn(parse_header))]
+    pub header: Vec<(HeaderName, HeaderValue)>,
+
+    /// only connect via IP version 4 or 6
+    #[argh(option)]
+    pub ip_version: Option<dns::IpVersion>,
+
+    /// resolve host to address instead of asking DNS ("host=address", repeatable)
+    #[argh(option, from_str_fn(dns::parse_override))]
+    pub resolve: Vec<(String, IpAddr)>,
+
+    /// capacity of each file's write buffer in KiB (larger suits NAS targets)
+    #[argh(option, default = "8")]
+    pub write_buffer: usize,
+}
+
+#[derive(FromArgs, ArgsInfo, Debug)]
+#[argh(
+    subcommand,
+    name = "doctor",
+    example = "Check everything a pull to photos would need:\n$ {command_name} -c client_secret.json photos",
+    example = "Check a setup using the token of an rclone remote:\n$ {command_name} --auth rclone:gphotos photos"
+)]
+/// Check secret, tokens, API and target folder before a big run
+pub struct DoctorArgs {
+    /// path to client secret file (the one you got from Google)
+    #[argh(option, short = 'c')]
+    pub client_secret: Option<PathBuf>,
+
+    /// where tokens come from: installed (browser flow, default), refresh-token:FILE or rclone:REMOTE
+    #[argh(option, default = "auth::Backend::default()")]
+    pub auth: auth::Backend,
+
+    /// target folder you intend to pull to
+    #[argh(positional)]
+    pub target: PathBuf,
+
+    /// only connect via IP version 4 or 6
+    #[argh(option)]
+    pub ip_version: Option<dns::IpVersion>,
+
+    /// resolve host to address instead of asking DNS ("host=address", repeatable)
+    #[argh(option, from_str_fn(dns::parse_override))]
+    pub resolve: Vec<(String, IpAddr)>,
+}
+
+#[derive(FromArgs, ArgsInfo, Debug)]
+#[argh(
+    subcommand,
+    name = "completions",
+    example = "Install completions for bash:\n$ {command_name} bash > ~/.local/share/bash-completion/completions/goopho",
+    example = "Install completions for fish:\n$ {command_name} fish > ~/.config/fish/completions/goopho.fish"
+)]
+/// Print a shell completion script to stdout
+pub struct CompletionsArgs {
+    /// shell to complete for (bash, zsh or fish)
+    #[argh(positional)]
+    pub shell: completions::Shell,
+}
+
+/// Headers to send with every download
+fn headers(user_agent: &Option<HeaderValue>, header: &[(HeaderName, HeaderValue)]) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    for (name, value) in header {
+        headers.append(name, value.clone());
+    }
+    if let Some(user_agent) = user_agent {
+        headers.insert(USER_AGENT, user_agent.clone());
+    }
+
+    headers
+}
+
+impl PullArgs {
+    /// Headers to send with every download
+    pub fn headers(&self) -> HeaderMap {
+        headers(&self.user_agent, &self.header)
+    }
+
+    /// Reject nonsensical combinations before any network or disk activity
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if let (Some(from_date), Some(to_date)) = (self.from_date, self.to_date) {
+            if from_date > to_date {
+                bail!(
+                    "--from-date {from_date} is later than --to-date {to_date}, nothing to select"
+                );
+            }
+        }
+
+        if self.concurrency == 0 {
+            bail!("--concurrency must allow at least one download");
+        }
+
+        if self.write_buffer == 0 {
+            bail!("--write-buffer needs at least 1 KiB");
+        }
+
+        if self.output.is_some() && !self.dry_run {
+            bail!("--output only applies to a dry run, add --dry-run or drop --output");
+        }
+
+        if self.verified_list.is_some() && self.dry_run {
+            bail!("--verified-list needs real downloads to verify, drop --dry-run");
+        }
+
+        if self.verified_list.is_some() && self.unordered {
+            bail!(
+                "--unordered fetches images re-encoded by Google, not originals to verify, \
+                drop --unordered or --verified-list"
+            );
+        }
+
+        if self.manifest.is_some() && self.dry_run {
+            bail!("--manifest records real downloads, drop --dry-run");
+        }
+
+        if self.sha256 && self.manifest.is_none() {
+            bail!("--sha256 digests are recorded in the manifest, add --manifest");
+        }
+
+        if (self.stop_after_files.is_some() || self.stop_after_bytes.is_some()) && self.dry_run {
+            bail!("--stop-after-files and --stop-after-bytes limit real downloads, drop --dry-run");
+        }
+
+        if self.stop_after_files == Some(0) || self.stop_after_bytes == Some(0) {
+            bail!("--stop-after-files and --stop-after-bytes need to allow something");
+        }
+
+        if self.resume && self.dry_run {
+            bail!("--continue needs a real pull to continue, drop --dry-run");
+        }
+
+        if !self.spill.is_empty() && self.capacity.is_none() {
+            bail!("--spill needs --capacity, or the target folder never fills up");
+        }
+
+        if !self.spill.is_empty() && self.dry_run {
+            bail!("--spill depends on sizes known while downloading, drop --dry-run");
+        }
+
+        if self.capacity == Some(0) || self.spill.iter().any(|(_, capacity)| *capacity == 0) {
+            bail!("--capacity and --spill need room for something");
+        }
+
+        for dir in std::iter::once(&self.target).chain(self.spill.iter().map(|(dir, _)| dir)) {
+            if dir.exists() && !self.resume {
+                bail!("Target dir {dir:?} exists, choose a folder that does not exist yet");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl PlanArgs {
+    /// Reject nonsensical combinations before any network or disk activity
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if let (Some(from_date), Some(to_date)) = (self.from_date, self.to_date) {
+            if from_date > to_date {
+                bail!(
+                    "--from-date {from_date} is later than --to-date {to_date}, nothing to select"
+                );
+            }
+        }
+
+        if self.target.exists() {
+            bail!(
+                "Target dir {:?} exists, choose a folder that does not exist yet",
+                self.target
+            );
+        }
+
+        if self.target.to_str().is_none() {
+            bail!(
+                "Target {:?} is no valid UTF-8, a plan file can't hold it",
+                self.target
+            );
+        }
+
+        Ok(())
+    }
+}
+
+impl ApplyArgs {
+    /// Headers to send with every download
+    pub fn headers(&self) -> HeaderMap {
+        headers(&self.user_agent, &self.header)
+    }
+
+    /// Reject nonsensical combinations before any network or disk activity
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.concurrency == 0 {
+            bail!("--concurrency must allow at least one download");
+        }
+
+        if self.write_buffer == 0 {
+            bail!("--write-buffer needs at least 1 KiB");
+        }
+
+        if self.sha256 && self.manifest.is_none() {
+            bail!("--sha256 digests are recorded in the manifest, add --manifest");
+        }
+
+        if self.stop_after_files == Some(0) || self.stop_after_bytes == Some(0) {
+            bail!("--stop-after-files and --stop-after-bytes need to allow something");
+        }
+
+        Ok(())
+    }
+}
+
+/// Parse a header value (visible ASCII only)
+fn parse_header_value(value: &str) -> Result<HeaderValue, String> {
+    HeaderValue::from_str(value).map_err(|e| format!("invalid header value '{value}': {e}"))
+}
+
+/// Parse a header given as `Name: value`
+fn parse_header(value: &str) -> Result<(HeaderName, HeaderValue), String> {
+    let (name, content) = value
+        .split_once(':')
+        .ok_or_else(|| format!("invalid header '{value}', expected 'Name: value'"))?;
+    let name = HeaderName::from_bytes(name.trim().as_bytes())
+        .map_err(|e| format!("invalid header name '{name}': {e}"))?;
+
+    Ok((name, parse_header_value(content.trim())?))
+}
+
+/// Parse a byte count, optionally with a decimal (KB, MB, GB, TB) or binary unit (KiB ... TiB)
+fn parse_bytes(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|e| format!("invalid byte count '{value}': {e}"))?;
+    let factor: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "kb" => 1000,
+        "mb" => 1000u64.pow(2),
+        "gb" => 1000u64.pow(3),
+        "tb" => 1000u64.pow(4),
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        "gib" => 1 << 30,
+        "tib" => 1 << 40,
+        _ => return Err(format!("unknown unit '{}' in '{value}'", unit.trim())),
+    };
+
+    number
+        .checked_mul(factor)
+        .ok_or_else(|| format!("byte count '{value}' is too large"))
+}
+
+/// Parse a spill folder given as `folder=capacity`
+fn parse_spill(value: &str) -> Result<(PathBuf, u64), String> {
+    let (dir, capacity) = value
+        .rsplit_once('=')
+        .ok_or_else(|| format!("invalid spill folder '{value}', expected 'folder=capacity'"))?;
+
+    Ok((PathBuf::from(dir), parse_bytes(capacity)?))
+}
+
+/// Date formats accepted on the command line
+const DATE_FORMATS: [&str; 3] = ["%Y-%m-%d", "%Y/%m/%d", "%Y%m%d"];
+
+/// Parse a date or a bare year, the latter resolved by `year_to_date`
+fn parse_date(
+    value: &str,
+    year_to_date: fn(i32) -> Option<NaiveDate>,
+) -> Result<NaiveDate, String> {
+    let value = value.trim();
+
+    if value.len() == 4 && value.chars().all(|c| c.is_ascii_digit()) {
+        let year: i32 = value
+            .parse()
+            .map_err(|e| format!("invalid year '{value}': {e}"))?;
+        return year_to_date(year).ok_or_else(|| format!("year '{value}' is out of range"));
+    }
+
+    DATE_FORMATS
+        .iter()
+        .find_map(|format| NaiveDate::parse_from_str(value, format).ok())
+        .ok_or_else(|| {
+            format!("invalid date '{value}', expected YYYY-MM-DD, YYYY/MM/DD, YYYYMMDD or YYYY")
+        })
+}
+
+/// Parse `--from-date`, a bare year meaning January 1st
+fn parse_from_date(value: &str) -> Result<NaiveDate, String> {
+    parse_date(value, |year| NaiveDate::from_ymd_opt(year, 1, 1))
+}
+
+/// Parse `--to-date`, a bare year meaning December 31st
+fn parse_to_date(value: &str) -> Result<NaiveDate, String> {
+    parse_date(value, |year| NaiveDate::from_ymd_opt(year, 12, 31))
 }
 
 /// Provide `OsString` to a file inside user's local data directory
@@ -63,6 +597,38 @@ pub fn get_token_store_path() -> anyhow::Result<OsString> {
     Ok(store)
 }
 
+/// Provide path to rclone's config file, looking where rclone does (in the same order)
+pub fn get_rclone_config_path() -> anyhow::Result<PathBuf> {
+    if let Some(path) = std::env::var_os("RCLONE_CONFIG") {
+        return Ok(PathBuf::from(path));
+    }
+    let Some(base_dirs) = BaseDirs::new() else {
+        bail!("Something is bad with your home directory");
+    };
+
+    let mut candidates = vec![];
+    if cfg!(windows) {
+        if let Some(app_data) = std::env::var_os("APPDATA") {
+            candidates.push(PathBuf::from(app_data).join("rclone").join("rclone.conf"));
+        }
+    }
+    if let Some(config_home) = std::env::var_os("XDG_CONFIG_HOME") {
+        candidates.push(
+            PathBuf::from(config_home)
+                .join("rclone")
+                .join("rclone.conf"),
+        );
+    }
+    let home = base_dirs.home_dir();
+    candidates.push(home.join(".config").join("rclone").join("rclone.conf"));
+    candidates.push(home.join(".rclone.conf"));
+
+    match candidates.iter().find(|path| path.is_file()) {
+        Some(path) => Ok(path.to_path_buf()),
+        None => bail!("No rclone config found in {candidates:?}, set RCLONE_CONFIG"),
+    }
+}
+
 /// Extract application secret from Google's `client_secret.json` file
 pub async fn get_app_secret(path: PathBuf) -> anyhow::Result<ApplicationSecret> {
     let client_secret = fs::read_to_string(path).await?;
@@ -73,3 +639,73 @@ pub async fn get_app_secret(path: PathBuf) -> anyhow::Result<ApplicationSecret>
 
     Ok(app_secret)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(year, month, day).unwrap()
+    }
+
+    #[test]
+    fn bare_year_spans_the_year() {
+        assert_eq!(parse_from_date("2021"), Ok(date(2021, 1, 1)));
+        assert_eq!(parse_to_date(" 2021 "), Ok(date(2021, 12, 31)));
+    }
+
+    #[test]
+    fn date_formats() {
+        for value in ["2021-03-04", "2021/03/04", "20210304"] {
+            assert_eq!(parse_from_date(value), Ok(date(2021, 3, 4)), "{value}");
+            assert_eq!(parse_to_date(value), Ok(date(2021, 3, 4)), "{value}");
+        }
+    }
+
+    #[test]
+    fn invalid_dates() {
+        for value in ["", "21", "2021-02-30", "2021-3", "04.03.2021", "next year"] {
+            assert!(parse_from_date(value).is_err(), "{value}");
+        }
+    }
+
+    #[test]
+    fn byte_units() {
+        assert_eq!(parse_bytes("512"), Ok(512));
+        assert_eq!(parse_bytes("512B"), Ok(512));
+        assert_eq!(parse_bytes("25GB"), Ok(25_000_000_000));
+        assert_eq!(parse_bytes("25 gb"), Ok(25_000_000_000));
+        assert_eq!(parse_bytes("4KiB"), Ok(4096));
+        assert_eq!(parse_bytes("1TiB"), Ok(1 << 40));
+    }
+
+    #[test]
+    fn invalid_byte_counts() {
+        for value in ["", "GB", "-1", "1.5GB", "10XB"] {
+            assert!(parse_bytes(value).is_err(), "{value}");
+        }
+        assert!(parse_bytes("20000000TB").is_err());
+        assert!(parse_bytes("99999999999999999999").is_err());
+    }
+
+    #[test]
+    fn headers() {
+        let (name, value) = parse_header("X-Trace: a:b ").unwrap();
+        assert_eq!(name, "x-trace");
+        assert_eq!(value, "a:b");
+
+        assert!(parse_header("X-Trace").is_err());
+        assert!(parse_header("Bad Name: value").is_err());
+        assert!(parse_header("X-Trace: line\nbreak").is_err());
+    }
+
+    #[test]
+    fn spill_splits_at_the_last_equals_sign() {
+        assert_eq!(
+            parse_spill("/mnt/a=b=4GiB"),
+            Ok((PathBuf::from("/mnt/a=b"), 4 << 30))
+        );
+        assert!(parse_spill("/mnt/disk").is_err());
+        assert!(parse_spill("/mnt/disk=lots").is_err());
+    }
+}
@@ -0,0 +1,139 @@
+//! DNS - system resolution with static overrides and IP version selection
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    str::FromStr,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use google_photoslibrary1 as photoslibrary1;
+use photoslibrary1::{
+    hyper::{
+        client::{
+            connect::dns::{GaiResolver, Name},
+            HttpConnector,
+        },
+        service::Service,
+    },
+    hyper_rustls::HttpsConnector,
+};
+
+/// Connector used for API calls and downloads alike
+pub type Connector = HttpsConnector<HttpConnector<Resolver>>;
+
+/// Restrict connections to one IP version
+#[derive(Clone, Copy, Debug)]
+pub enum IpVersion {
+    V4,
+    V6,
+}
+
+impl IpVersion {
+    fn name(&self) -> &'static str {
+        match self {
+            IpVersion::V4 => "IPv4",
+            IpVersion::V6 => "IPv6",
+        }
+    }
+
+    fn matches(&self, addr: &SocketAddr) -> bool {
+        match self {
+            IpVersion::V4 => addr.is_ipv4(),
+            IpVersion::V6 => addr.is_ipv6(),
+        }
+    }
+}
+
+impl FromStr for IpVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "4" => Ok(IpVersion::V4),
+            "6" => Ok(IpVersion::V6),
+            other => Err(format!("unknown IP version '{other}', expected 4 or 6")),
+        }
+    }
+}
+
+/// Parse a static override given as `host=address`
+pub fn parse_override(value: &str) -> Result<(String, IpAddr), String> {
+    let (host, addr) = value
+        .split_once('=')
+        .ok_or_else(|| format!("invalid override '{value}', expected 'host=address'"))?;
+    let addr = addr
+        .trim()
+        .parse()
+        .map_err(|e| format!("invalid address in '{value}': {e}"))?;
+
+    Ok((host.trim().to_lowercase(), addr))
+}
+
+/// Resolve hostnames, preferring static overrides
+#[derive(Clone)]
+pub struct Resolver {
+    overrides: Arc<HashMap<String, Vec<IpAddr>>>,
+    ip_version: Option<IpVersion>,
+    system: GaiResolver,
+}
+
+impl Resolver {
+    pub fn new(overrides: &[(String, IpAddr)], ip_version: Option<IpVersion>) -> Self {
+        let mut by_host: HashMap<String, Vec<IpAddr>> = HashMap::new();
+        for (host, addr) in overrides {
+            by_host.entry(host.clone()).or_default().push(*addr);
+        }
+
+        Resolver {
+            overrides: Arc::new(by_host),
+            ip_version,
+            system: GaiResolver::new(),
+        }
+    }
+}
+
+impl Service<Name> for Resolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.system.poll_ready(cx)
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let host = name.as_str().to_lowercase();
+        let overridden = self.overrides.get(&host).cloned();
+        let ip_version = self.ip_version;
+        let mut system = self.system.clone();
+
+        Box::pin(async move {
+            // Ports get filled in by the connector
+            let addrs: Vec<SocketAddr> = match overridden {
+                Some(ips) => ips.into_iter().map(|ip| SocketAddr::new(ip, 0)).collect(),
+                None => system.call(name).await?.collect(),
+            };
+            let addrs: Vec<SocketAddr> = addrs
+                .into_iter()
+                .filter(|addr| ip_version.is_none_or(|v| v.matches(addr)))
+                .collect();
+
+            if addrs.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!(
+                        "no {} address for {host}",
+                        ip_version.map_or("any", |v| v.name())
+                    ),
+                ));
+            }
+
+            Ok(addrs.into_iter())
+        })
+    }
+}
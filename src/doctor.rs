@@ -0,0 +1,186 @@
+//! Doctor - check the setup before users commit to a big run
+
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use google_photoslibrary1 as photoslibrary1;
+use photoslibrary1::{
+    chrono::{TimeZone, Utc},
+    oauth2::storage::TokenInfo,
+};
+use tokio::fs;
+
+use crate::{auth, config, dns};
+
+const API_TIMEOUT_MS: u64 = 10000;
+const MIN_FREE_BYTES: u64 = 1 << 30;
+const PROBE_FILE: &str = ".goopho-doctor";
+
+/// Run all checks and print a pass/fail checklist
+pub async fn run(args: config::DoctorArgs) -> anyhow::Result<()> {
+    let mut failed = 0;
+    let mut report = |name: &str, result: anyhow::Result<String>| -> bool {
+        match result {
+            Ok(detail) => {
+                println!("[ ok ] {name}: {detail}");
+                true
+            }
+            Err(e) => {
+                failed += 1;
+                println!("[FAIL] {name}: {e:#}");
+                false
+            }
+        }
+    };
+
+    let secret_ok = report(
+        "client secret",
+        check_client_secret(args.client_secret.as_deref(), &args.auth).await,
+    );
+    let tokens_ok = report("tokens", check_tokens(&args.auth).await);
+    if secret_ok && tokens_ok {
+        let resolver = dns::Resolver::new(&args.resolve, args.ip_version);
+        report(
+            "API and quota",
+            check_api(args.client_secret, args.auth, resolver).await,
+        );
+    } else {
+        report(
+            "API and quota",
+            Err(anyhow::anyhow!(
+                "skipped, needs valid client secret and tokens"
+            )),
+        );
+    }
+
+    // The target itself must not exist, so look at the folder it will be created in
+    let parent = match args.target.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => Path::new(".").to_path_buf(),
+    };
+    report("target folder", check_target(&args.target, &parent).await);
+    report("free space", check_free_space(&parent));
+
+    if failed > 0 {
+        bail!("{failed} check(s) failed");
+    }
+
+    Ok(())
+}
+
+/// Secret file is readable and in `installed` format (unless rclone brings its own)
+async fn check_client_secret(
+    path: Option<&Path>,
+    backend: &auth::Backend,
+) -> anyhow::Result<String> {
+    if let auth::Backend::Rclone(remote) = backend {
+        return Ok(format!(
+            "not needed, rclone remote '{remote}' brings its own"
+        ));
+    }
+    let path = path.context("--client-secret is required, unless using --auth rclone:REMOTE")?;
+    let secret = config::get_app_secret(path.to_path_buf()).await?;
+
+    Ok(format!("client id {}", secret.client_id))
+}
+
+/// Refresh token is where the backend looks for it
+async fn check_tokens(backend: &auth::Backend) -> anyhow::Result<String> {
+    match backend {
+        auth::Backend::Installed => check_token_store().await,
+        auth::Backend::RefreshToken(path) => {
+            let refresh_token = fs::read_to_string(path)
+                .await
+                .with_context(|| format!("cannot read refresh token from {path:?}"))?;
+            if refresh_token.trim().is_empty() {
+                bail!("{path:?} is empty, expected a refresh token");
+            }
+            Ok(format!("refresh token in {path:?}"))
+        }
+        auth::Backend::Rclone(remote) => {
+            let (client_id, _, _) = auth::rclone_token(remote).await?;
+            Ok(format!(
+                "refresh token of rclone remote '{remote}', client id {client_id}"
+            ))
+        }
+    }
+}
+
+/// Token store holds a refresh token, access token might be stale
+async fn check_token_store() -> anyhow::Result<String> {
+    let store = config::get_token_store_path()?;
+    let content = fs::read_to_string(&store)
+        .await
+        .with_context(|| format!("no tokens in {store:?}, first pull asks for authorization"))?;
+    let entries: Vec<serde_json::Value> = serde_json::from_str(&content)?;
+
+    for entry in entries {
+        let token: TokenInfo = serde_json::from_value(entry["token"].clone())?;
+        if token.refresh_token.is_none() {
+            continue;
+        }
+        // RFC 3339, like other timestamps goopho writes
+        let expires_at = token.expires_at.map(|at| {
+            let seconds = at.unix_timestamp();
+            Utc.timestamp_opt(seconds, 0)
+                .single()
+                .map_or_else(|| seconds.to_string(), |at| at.to_rfc3339())
+        });
+        return Ok(match expires_at {
+            Some(expires_at) if token.is_expired() => {
+                format!("access token expired {expires_at}, will be refreshed")
+            }
+            Some(expires_at) => format!("access token valid until {expires_at}"),
+            None => "refresh token present".to_string(),
+        });
+    }
+
+    bail!("no refresh token in {store:?}, delete it and authorize again")
+}
+
+/// A cheap list call succeeds (also proving quota is not exhausted)
+async fn check_api(
+    client_secret: Option<PathBuf>,
+    backend: auth::Backend,
+    resolver: dns::Resolver,
+) -> anyhow::Result<String> {
+    let (_, hub) = auth::connect(client_secret, backend, resolver).await?;
+    let (response, _) = tokio::time::timeout(
+        Duration::from_millis(API_TIMEOUT_MS),
+        hub.media_items().list().page_size(1).doit(),
+    )
+    .await
+    .context("timeout listing media items")??;
+
+    Ok(format!("listing returned HTTP {}", response.status()))
+}
+
+/// Target does not exist yet and its parent is writable
+async fn check_target(target: &Path, parent: &Path) -> anyhow::Result<String> {
+    if fs::metadata(target).await.is_ok() {
+        bail!("{target:?} exists, pull needs a folder that does not exist yet");
+    }
+
+    let probe = parent.join(PROBE_FILE);
+    fs::write(&probe, b"")
+        .await
+        .with_context(|| format!("cannot write to {parent:?}"))?;
+    fs::remove_file(&probe).await?;
+
+    Ok(format!("{parent:?} is writable"))
+}
+
+/// Enough room left on the target's file system
+fn check_free_space(parent: &Path) -> anyhow::Result<String> {
+    let available = fs4::available_space(parent)?;
+    let gib = available as f64 / (1u64 << 30) as f64;
+
+    if available < MIN_FREE_BYTES {
+        bail!("only {gib:.2} GiB available on {parent:?}");
+    }
+
+    Ok(format!("{gib:.2} GiB available"))
+}
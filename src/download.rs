@@ -1,143 +1,375 @@
 //! Handling of (potentially massive) asynchronous downloads and disk writes
 
-use std::{path::PathBuf, str::FromStr, time::Duration};
+use std::{
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
+use anyhow::bail;
 use async_recursion::async_recursion;
 use futures::{self, future, StreamExt};
 use google_photoslibrary1 as photoslibrary1;
-use photoslibrary1::{
-    hyper::{self, body::HttpBody, client::HttpConnector, StatusCode},
-    hyper_rustls::HttpsConnector,
-};
+use photoslibrary1::hyper::{self, body::HttpBody, HeaderMap, StatusCode};
 use rand::Rng;
+use sha2::{Digest, Sha256};
 use tokio::{
     fs,
     io::{self, AsyncWriteExt},
     sync::mpsc,
     task::JoinHandle,
 };
-use tracing::instrument;
+use tracing::{debug, info, instrument};
 
-use crate::{acc::Event, hub::MediaAttr};
+use crate::{
+    acc::{Event, Transfer},
+    dns, dryrun,
+    hub::{Checkpoint, MediaAttr},
+    targets::Targets,
+    throttle::{Permit, Throttle},
+};
 
 const IN_PROGRESS_SUFFIX: &str = ".chunks";
 const TIMEOUT_MS: u64 = 3000;
 
+/// Shared by all downloads and disk writes, cheap to clone
+#[derive(Clone)]
+pub struct WriteContext {
+    pub client: hyper::Client<dns::Connector>,
+    pub track_and_log: mpsc::Sender<Event>,
+    pub targets: Arc<Targets>,
+    pub dry_run: Option<mpsc::Sender<dryrun::Row>>,
+    pub throttle: Arc<Throttle>,
+    pub headers: HeaderMap,
+    pub write_buffer: usize,
+    pub sha256: bool,
+    pub retry_budget: Option<Arc<RetryBudget>>,
+    pub quota: Arc<Quota>,
+    pub skip_existing: bool,
+    pub checkpoint: Option<Arc<Checkpoint>>,
+}
+
+impl WriteContext {
+    /// Fail once retries across the run used up the budget (if there is one)
+    fn check_budget(&self) -> anyhow::Result<()> {
+        match &self.retry_budget {
+            Some(budget) => budget.check(),
+            None => Ok(()),
+        }
+    }
+
+    /// Let the listing checkpoint move past an item written or failed (if there is one)
+    async fn done(&self, id: &str) -> anyhow::Result<()> {
+        match &self.checkpoint {
+            Some(checkpoint) => checkpoint.done(id).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Count a retry against the budget
+    fn spend_retry(&self) -> anyhow::Result<()> {
+        match &self.retry_budget {
+            Some(budget) => budget.spend(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Retries allowed across all downloads of a run
+pub struct RetryBudget {
+    limit: u32,
+    spent: AtomicU32,
+}
+
+impl RetryBudget {
+    pub fn new(limit: u32) -> Arc<Self> {
+        Arc::new(RetryBudget {
+            limit,
+            spent: AtomicU32::new(0),
+        })
+    }
+
+    fn check(&self) -> anyhow::Result<()> {
+        if self.spent.load(Ordering::Relaxed) > self.limit {
+            bail!(
+                "Retry budget of {} used up, stopping early (expired auth or an outage?)",
+                self.limit
+            );
+        }
+        Ok(())
+    }
+
+    fn spend(&self) -> anyhow::Result<()> {
+        self.spent.fetch_add(1, Ordering::Relaxed);
+        self.check()
+    }
+}
+
+/// Limits on what a run writes, for filling fixed-size media
+pub struct Quota {
+    max_files: Option<u64>,
+    max_bytes: Option<u64>,
+    files: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl Quota {
+    pub fn new(max_files: Option<u64>, max_bytes: Option<u64>) -> Arc<Self> {
+        Arc::new(Quota {
+            max_files,
+            max_bytes,
+            files: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+        })
+    }
+
+    /// Whether the run should take no more items
+    pub fn reached(&self) -> bool {
+        self.max_files
+            .is_some_and(|max| self.files.load(Ordering::Relaxed) >= max)
+            || self
+                .max_bytes
+                .is_some_and(|max| self.bytes.load(Ordering::Relaxed) >= max)
+    }
+
+    /// Claim one of the files left, exact for files but soft for bytes
+    ///  (Downloads in flight still finish when the byte limit is hit.)
+    fn claim(&self) -> bool {
+        if self
+            .max_bytes
+            .is_some_and(|max| self.bytes.load(Ordering::Relaxed) >= max)
+        {
+            return false;
+        }
+        self.files
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |files| {
+                self.max_files
+                    .is_none_or(|max| files < max)
+                    .then_some(files + 1)
+            })
+            .is_ok()
+    }
+
+    /// Count bytes written, handing back the claim of incomplete downloads
+    fn settle(&self, transfer: &Transfer) {
+        if transfer.completed {
+            self.bytes.fetch_add(transfer.offset, Ordering::Relaxed);
+        } else {
+            self.release();
+        }
+    }
+
+    /// Hand back a claim
+    fn release(&self) {
+        self.files.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
 /// Spawn green threads to do the heavy lifting
 pub async fn photos_to_disk(
     mut write_request: mpsc::Receiver<MediaAttr>,
-    track_and_log: mpsc::Sender<Event>,
-    download_dir: PathBuf,
-    client: hyper::Client<HttpsConnector<HttpConnector>>,
-    is_dry_run: bool,
+    ctx: WriteContext,
 ) -> JoinHandle<anyhow::Result<()>> {
     // Schedule downloads and disk writes
     tokio::spawn(async move {
         let mut handles = vec![];
+        let mut idle = Duration::ZERO;
 
-        while let Some(item) = write_request.recv().await {
-            let (url, filename, creation_time) = match item {
-                MediaAttr::ImageOrMotionPhotoBaseUrl(url, name, width, height, ctime) => {
-                    // (url + &format!("=w{width}-h{height}"), name, ctime)
-                    (url + "=d", name, ctime)
-                }
-                MediaAttr::VideoBaseUrl(url, name, ctime) => (url + "=dv", name, ctime),
+        loop {
+            // Idle time tells whether listing keeps up with writers
+            let waiting = Instant::now();
+            let Some(item) = write_request.recv().await else {
+                break;
             };
+            idle += waiting.elapsed();
 
-            // TODO: Prepare this just before you're really about to download.
-            let mut path = download_dir.clone();
-            let http_cli = client.clone();
-            let track_and_log = track_and_log.clone();
-            let mut rng = rand::thread_rng();
-            let sleep_seed = rng.gen_range(TIMEOUT_MS..(TIMEOUT_MS + TIMEOUT_MS / 2));
-
-            let write_thread: JoinHandle<anyhow::Result<()>> = tokio::spawn(async move {
-                path.push(&filename);
-                if is_dry_run {
-                    println!("dry_run,\"{creation_time}\",{path:?}");
-                } else {
-                    track_and_log.send(Event::New).await?;
-                    download_and_write(http_cli, url, path, track_and_log, sleep_seed).await?;
-                }
+            // Wait for a slot before taking the next item, so the queue applies back-pressure
+            //  (Permits are handed out FIFO, so bounded concurrency keeps the order.)
+            let permit = ctx.throttle.acquire().await?;
 
-                Ok(())
-            });
+            // Stop taking requests, which also ends listing
+            //  (Downloads in flight still finish, so their outcome is recorded.)
+            if ctx.check_budget().is_err() {
+                write_request.close();
+                break;
+            }
+            if ctx.quota.reached() {
+                info!("Quota reached, taking no more items");
+                write_request.close();
+                break;
+            }
+            if ctx.targets.full() {
+                info!("No target has room left, taking no more items");
+                write_request.close();
+                break;
+            }
+
+            let write_thread: JoinHandle<anyhow::Result<()>> =
+                tokio::spawn(write_item(item, true, Some(permit), ctx.clone()));
 
             handles.push(write_thread);
         }
 
-        future::try_join_all(handles).await?;
-        Ok(())
+        info!("Writer idle for {idle:.2?} waiting on listing");
+        for written in future::try_join_all(handles).await? {
+            written?;
+        }
+        ctx.check_budget()
     })
 }
 
 /// Spawn green threads with some control over concurrency (experimental)
 pub async fn photos_to_disk_unordered(
     mut write_request: mpsc::Receiver<MediaAttr>,
-    track_and_log: mpsc::Sender<Event>,
-    download_dir: PathBuf,
-    client: hyper::Client<HttpsConnector<HttpConnector>>,
-    is_dry_run: bool,
+    ctx: WriteContext,
 ) -> JoinHandle<anyhow::Result<()>> {
     // Schedule downloads and disk writes
     tokio::spawn(async move {
         let mut media_items = Vec::<MediaAttr>::new();
+        let waiting = Instant::now();
         while let Some(item) = write_request.recv().await {
             media_items.push(item);
         }
-        let fetches = futures::stream::iter(media_items.into_iter().map(|item| {
-            let (url, filename, creation_time) = match item {
-                MediaAttr::ImageOrMotionPhotoBaseUrl(url, name, width, height, ctime) => {
-                    (url + &format!("=w{width}-h{height}"), name, ctime)
-                }
-                MediaAttr::VideoBaseUrl(url, name, ctime) => (url + "=dv", name, ctime),
-            };
-
-            // TODO: Prepare this just before you're really about to download.
-            let mut path = download_dir.clone();
-            let http_cli = client.clone();
-            let track_and_log = track_and_log.clone();
-            let mut rng = rand::thread_rng();
-            let sleep_seed = rng.gen_range(TIMEOUT_MS..(TIMEOUT_MS + TIMEOUT_MS / 2));
-
-            async move {
-                path.push(&filename);
-                if is_dry_run {
-                    println!("dry_run,\"{creation_time}\",{path:?}");
-                    Ok(())
-                } else {
-                    track_and_log.send(Event::New).await?;
-                    download_and_write(http_cli, url, path, track_and_log, sleep_seed).await
-                }
-            }
-        }))
-        .buffer_unordered(100)
+        info!(
+            "Writer idle for {:.2?} collecting listing",
+            waiting.elapsed()
+        );
+        let fetches = futures::stream::iter(
+            media_items
+                .into_iter()
+                .map(|item| write_item(item, false, None, ctx.clone())),
+        )
+        .buffer_unordered(ctx.throttle.max())
         .collect::<Vec<anyhow::Result<()>>>();
 
-        fetches.await;
-        Ok(())
+        for written in fetches.await {
+            written?;
+        }
+        ctx.check_budget()
     })
 }
 
+/// Download and write a single media item (or just list it on dry runs)
+///  (Takes a slot to download in, unless the caller already holds one.)
+async fn write_item(
+    item: MediaAttr,
+    original: bool,
+    permit: Option<Permit>,
+    ctx: WriteContext,
+) -> anyhow::Result<()> {
+    let MediaAttr {
+        id,
+        base_url,
+        product_url,
+        filename,
+        mime,
+        dimensions,
+        creation_time,
+    } = item;
+    let url = match dimensions {
+        // Original bytes or full size (re-encoded by Google)
+        Some(_) if original => base_url + "=d",
+        Some((width, height)) => base_url + &format!("=w{width}-h{height}"),
+        None => base_url + "=dv",
+    };
+
+    if let Some(listing) = ctx.dry_run {
+        listing
+            .send(dryrun::Row {
+                id,
+                path: ctx.targets.first().join(&filename),
+                mime,
+                width: dimensions.map(|(w, _)| w),
+                height: dimensions.map(|(_, h)| h),
+                creation_time,
+            })
+            .await?;
+        return Ok(());
+    }
+
+    ctx.check_budget()?;
+    if ctx.skip_existing && ctx.targets.contain(&filename).await {
+        debug!("Skipping {filename}, written before");
+        ctx.track_and_log.send(Event::Skipped).await?;
+        return ctx.done(&id).await;
+    }
+
+    let sleep_seed = rand::thread_rng().gen_range(TIMEOUT_MS..(TIMEOUT_MS + TIMEOUT_MS / 2));
+    let permit = match permit {
+        Some(permit) => permit,
+        None => ctx.throttle.acquire().await?,
+    };
+    // (Left to a continued pull, once targets are full or the quota is reached.)
+    if ctx.targets.full() || !ctx.quota.claim() {
+        ctx.track_and_log.send(Event::Skipped).await?;
+        return Ok(());
+    }
+    let track_and_log = ctx.track_and_log.clone();
+    track_and_log.send(Event::New).await?;
+    let Some((path, transfer)) = download_and_write(&ctx, url, filename, sleep_seed).await? else {
+        // No target has room for it
+        ctx.quota.release();
+        track_and_log.send(Event::Skipped).await?;
+        return Ok(());
+    };
+    drop(permit);
+    ctx.quota.settle(&transfer);
+
+    if transfer.retries > 0 || transfer.throttled {
+        ctx.throttle.congestion();
+    } else {
+        ctx.throttle.success();
+    }
+
+    if transfer.completed {
+        track_and_log
+            .send(Event::Completed(id.clone(), product_url, path.clone()))
+            .await?;
+    }
+    // Failures count as done too (the manifest has them), unless the budget cut them short
+    let budget = ctx.check_budget();
+    if transfer.completed || budget.is_ok() {
+        ctx.done(&id).await?;
+    }
+    track_and_log
+        .send(Event::Transferred(id, path, transfer))
+        .await?;
+
+    // Stop once the outcome is recorded, if the budget ran out meanwhile
+    budget
+}
+
 /// Used with progress indicator
-#[instrument(name = "downloading", skip(http_cli, url, track_and_log, sleep_seed))]
+///  (None when no target has room for the download.)
+#[instrument(name = "downloading", skip(ctx, url, sleep_seed))]
 #[async_recursion]
 async fn download_and_write(
-    http_cli: hyper::Client<HttpsConnector<HttpConnector>>,
+    ctx: &WriteContext,
     url: String,
-    path: PathBuf,
-    track_and_log: mpsc::Sender<Event>,
+    filename: String,
     sleep_seed: u64,
-) -> anyhow::Result<()> {
+) -> anyhow::Result<Option<(PathBuf, Transfer)>> {
     let uri = hyper::Uri::from_str(&url)?;
+    let track_and_log = &ctx.track_and_log;
 
     // Timeout/retry
     let mut pause_ms = sleep_seed;
+    let mut transfer = Transfer::default();
 
     let mut res;
     loop {
-        match tokio::time::timeout(Duration::from_millis(TIMEOUT_MS), http_cli.get(uri.clone()))
-            .await
+        let mut request = hyper::Request::get(uri.clone()).body(hyper::Body::empty())?;
+        *request.headers_mut() = ctx.headers.clone();
+
+        match tokio::time::timeout(
+            Duration::from_millis(TIMEOUT_MS),
+            ctx.client.request(request),
+        )
+        .await
         {
             Ok(response) => {
                 res = response.unwrap(); // TODO: Handle connection resets from here.
@@ -145,6 +377,17 @@ async fn download_and_write(
             }
             Err(_) => {
                 // Timeout branch
+                transfer.retries += 1;
+                if ctx.spend_retry().is_err() {
+                    let path = ctx.targets.first().join(&filename);
+                    track_and_log
+                        .send(Event::Failed(
+                            path.display().to_string(),
+                            "retry budget used up",
+                        ))
+                        .await?;
+                    return Ok(Some((path, transfer)));
+                }
                 track_and_log
                     .send(Event::RetryAfter(url.clone(), pause_ms))
                     .await?;
@@ -158,8 +401,21 @@ async fn download_and_write(
     // Check HTTP status codes
     match res.status() {
         StatusCode::OK => {
-            let chunks_written = path.to_string_lossy().to_string() + IN_PROGRESS_SUFFIX;
-            let mut outfile = io::BufWriter::new(fs::File::create(&chunks_written).await?);
+            // Pick a target with room, now that the size is known (if sent)
+            let Some(reservation) = ctx.targets.reserve(res.body().size_hint().exact()) else {
+                return Ok(None);
+            };
+            let path = reservation.dir.join(&filename);
+            let mut chunks_written = path.clone().into_os_string();
+            chunks_written.push(IN_PROGRESS_SUFFIX);
+            let chunks_written = PathBuf::from(chunks_written);
+            // (Chunks larger than the buffer bypass it and go to disk in one write.)
+            let mut outfile = io::BufWriter::with_capacity(
+                ctx.write_buffer,
+                fs::File::create(&chunks_written).await?,
+            );
+            // Hash chunks on their way to disk, saving a second pass
+            let mut hasher = ctx.sha256.then(Sha256::new);
             let mut timeouts: u8 = 0;
             let mut completed = false;
 
@@ -177,58 +433,97 @@ async fn download_and_write(
                         }
                         Err(_) => {
                             timeouts += 1;
+                            transfer.retries += 1;
+                            if ctx.spend_retry().is_err() {
+                                track_and_log
+                                    .send(Event::Failed(
+                                        chunks_written.display().to_string(),
+                                        "retry budget used up",
+                                    ))
+                                    .await?;
+                                break;
+                            }
                             if timeouts.rem_euclid(60u8) == 0 {
                                 track_and_log
-                                    .send(Event::Failed(chunks_written.clone()))
+                                    .send(Event::Failed(
+                                        chunks_written.display().to_string(),
+                                        "timed out reading",
+                                    ))
                                     .await?;
                                 break;
                             } else if timeouts.rem_euclid(20u8) == 0 {
                                 track_and_log
-                                    .send(Event::Retrying(chunks_written.clone(), timeouts))
+                                    .send(Event::Retrying(
+                                        chunks_written.display().to_string(),
+                                        timeouts,
+                                    ))
                                     .await?;
                             }
                             continue;
                         }
                     };
-                outfile.write_all(&chunk?).await?;
+                let chunk = chunk?;
+                if let Some(hasher) = hasher.as_mut() {
+                    hasher.update(&chunk);
+                }
+                outfile.write_all(&chunk).await?;
+                transfer.offset += chunk.len() as u64;
             }
 
             outfile.flush().await?;
+            ctx.targets.settle(reservation, transfer.offset);
             if completed {
                 fs::rename(&chunks_written, &path).await?;
-                track_and_log.send(Event::Completed).await?;
+            } else {
+                // (Without Range resume, a continued pull starts over anyway.)
+                fs::remove_file(&chunks_written).await?;
             }
             // eprintln!("Wrote {path:?}");
+            transfer.completed = completed;
+            if completed {
+                transfer.sha256 = hasher.map(|hasher| format!("{:x}", hasher.finalize()));
+            }
+            Ok(Some((path, transfer)))
         }
         StatusCode::FOUND => {
             if let Some(header) = res.headers().get("location") {
                 let location = header.to_str()?;
                 // Recursion
-                download_and_write(
-                    http_cli,
-                    location.to_string(),
+                let Some((path, redirected)) =
+                    download_and_write(ctx, location.to_string(), filename, pause_ms).await?
+                else {
+                    return Ok(None);
+                };
+                Ok(Some((
                     path,
-                    track_and_log.clone(),
-                    pause_ms,
-                )
-                .await?;
+                    Transfer {
+                        retries: transfer.retries + redirected.retries,
+                        ..redirected
+                    },
+                )))
             } else {
                 // When location in 302 was invalid:
+                let path = ctx.targets.first().join(&filename);
                 track_and_log
-                    .send(Event::Failed(path.to_string_lossy().to_string()))
+                    .send(Event::Failed(
+                        path.display().to_string(),
+                        "invalid redirect",
+                    ))
                     .await?;
+                Ok(Some((path, transfer)))
             }
         }
         // Catch all
-        _ => {
+        status => {
+            let path = ctx.targets.first().join(&filename);
+            transfer.throttled = status == StatusCode::TOO_MANY_REQUESTS;
             track_and_log
                 .send(Event::FailedHttp(
-                    path.to_string_lossy().to_string(),
+                    path.display().to_string(),
                     res.status().to_string(),
                 ))
                 .await?;
+            Ok(Some((path, transfer)))
         }
     }
-
-    Ok(())
 }
@@ -0,0 +1,156 @@
+//! Dry-run listing - what would be written, as CSV
+
+use std::{fmt, path::PathBuf, str::FromStr};
+
+use anyhow::Context;
+use google_photoslibrary1 as photoslibrary1;
+use photoslibrary1::chrono::{DateTime, Utc};
+use tokio::{
+    fs,
+    io::{self, AsyncWrite, AsyncWriteExt},
+    sync::mpsc,
+    task::JoinHandle,
+};
+
+/// Columns available for output
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Column {
+    CreationTime,
+    Path,
+    Id,
+    Mime,
+    Width,
+    Height,
+}
+
+impl Column {
+    fn name(&self) -> &'static str {
+        match self {
+            Column::CreationTime => "creation_time",
+            Column::Path => "path",
+            Column::Id => "id",
+            Column::Mime => "mime",
+            Column::Width => "width",
+            Column::Height => "height",
+        }
+    }
+}
+
+impl FromStr for Column {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "creation_time" => Ok(Column::CreationTime),
+            "path" => Ok(Column::Path),
+            "id" => Ok(Column::Id),
+            "mime" => Ok(Column::Mime),
+            "width" => Ok(Column::Width),
+            "height" => Ok(Column::Height),
+            other => Err(format!(
+                "unknown column '{other}', expected one of: \
+                creation_time, path, id, mime, width, height"
+            )),
+        }
+    }
+}
+
+/// Comma separated list of columns (as given on the command line)
+#[derive(Clone, Debug)]
+pub struct Columns(Vec<Column>);
+
+impl Default for Columns {
+    fn default() -> Self {
+        Columns(vec![Column::CreationTime, Column::Path])
+    }
+}
+
+impl FromStr for Columns {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let columns = s
+            .split(',')
+            .map(Column::from_str)
+            .collect::<Result<Vec<Column>, String>>()?;
+        Ok(Columns(columns))
+    }
+}
+
+impl fmt::Display for Columns {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<&str> = self.0.iter().map(Column::name).collect();
+        write!(f, "{}", names.join(","))
+    }
+}
+
+/// One media file that would have been written
+pub struct Row {
+    pub id: String,
+    pub path: PathBuf,
+    pub mime: String,
+    pub width: Option<i64>,
+    pub height: Option<i64>,
+    pub creation_time: DateTime<Utc>,
+}
+
+impl Row {
+    fn field(&self, column: Column) -> String {
+        match column {
+            Column::CreationTime => self.creation_time.to_rfc3339(),
+            Column::Path => self.path.to_string_lossy().to_string(),
+            Column::Id => self.id.clone(),
+            Column::Mime => self.mime.clone(),
+            Column::Width => self.width.map(|w| w.to_string()).unwrap_or_default(),
+            Column::Height => self.height.map(|h| h.to_string()).unwrap_or_default(),
+        }
+    }
+}
+
+/// Quote a field if required (RFC 4180)
+pub fn quote(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Where the listing goes
+pub type Sink = Box<dyn AsyncWrite + Unpin + Send>;
+
+/// Create the output file (or use stdout if none given), before any network activity
+pub async fn open(output: Option<PathBuf>) -> anyhow::Result<Sink> {
+    Ok(match output {
+        Some(path) => Box::new(
+            fs::File::create(&path)
+                .await
+                .with_context(|| format!("Failed to create {path:?}"))?,
+        ),
+        None => Box::new(io::stdout()),
+    })
+}
+
+/// Write header and rows to sink
+pub async fn write_rows(
+    mut rows: mpsc::Receiver<Row>,
+    columns: Columns,
+    sink: Sink,
+) -> JoinHandle<anyhow::Result<()>> {
+    tokio::spawn(async move {
+        let mut out = io::BufWriter::new(sink);
+
+        let header: Vec<&str> = columns.0.iter().map(Column::name).collect();
+        out.write_all((header.join(",") + "\r\n").as_bytes())
+            .await?;
+
+        while let Some(row) = rows.recv().await {
+            let fields: Vec<String> = columns.0.iter().map(|c| quote(&row.field(*c))).collect();
+            out.write_all((fields.join(",") + "\r\n").as_bytes())
+                .await?;
+        }
+
+        out.flush().await?;
+        Ok(())
+    })
+}
@@ -1,38 +1,303 @@
 //! Selection of media files to download
 
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, VecDeque},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
 
+use anyhow::bail;
 use google_photoslibrary1 as photoslibrary1;
 use photoslibrary1::{
     api::{ListMediaItemsResponse, MediaItem, MediaMetadata},
     chrono::{DateTime, NaiveDate, Utc},
-    hyper::client::HttpConnector,
-    hyper_rustls::HttpsConnector,
     Error, PhotosLibrary,
 };
-use tokio::sync::mpsc;
-use tracing::{error, info, warn};
+use serde_json::{json, Value};
+use tokio::{
+    fs,
+    sync::{mpsc, Mutex},
+};
+use tracing::{debug, error, info, warn};
+
+use crate::dns;
+
+const CHECKPOINT_FILE: &str = ".goopho-listing.json";
+const CHECKPOINT_EVERY_PAGES: u64 = 10;
+const EXAMPLES_PER_REASON: u32 = 3;
 
 /// Attributes of `MediaItem` to download
 #[derive(Clone)]
-pub enum MediaAttr {
-    // URL, filename, width, height, creation time
-    ImageOrMotionPhotoBaseUrl(String, String, i64, i64, DateTime<Utc>),
-    // URL, filename, creation time
-    VideoBaseUrl(String, String, DateTime<Utc>),
+pub struct MediaAttr {
+    pub id: String,
+    pub base_url: String,
+    pub product_url: String,
+    pub filename: String,
+    pub mime: String,
+    // Photos (and motion photos) only, videos have none
+    pub dimensions: Option<(i64, i64)>,
+    pub creation_time: DateTime<Utc>,
+}
+
+/// Counters of filenames seen, so repeated ones get a goo_N_ prefix
+#[derive(Default)]
+pub struct Filenames {
+    counts: HashMap<String, u16>,
+    // Counters changed since last taken
+    changed: Vec<String>,
+}
+
+impl Filenames {
+    /// Name to write an item to, counting the original name
+    fn unique(&mut self, filename: &str) -> String {
+        self.changed.push(filename.to_string());
+        if let Some(v) = self.counts.get_mut(filename) {
+            *v += 1;
+            format!("goo_{v}_{filename}")
+        } else {
+            self.counts.insert(filename.to_string(), 0);
+            filename.to_string()
+        }
+    }
+
+    /// Counters changed since last taken (for the checkpoint to catch up on)
+    fn take_changed(&mut self) -> Vec<(String, u16)> {
+        self.changed
+            .drain(..)
+            .map(|filename| {
+                let count = self.counts[&filename];
+                (filename, count)
+            })
+            .collect()
+    }
+}
+
+/// Listing position persisted in the target folder, to continue interrupted pulls
+///  (Pages only count as listed once writers are done with all of their items.)
+pub struct Checkpoint {
+    path: PathBuf,
+    filters: Value,
+    progress: Mutex<Progress>,
 }
 
-/// Collect attributes of `MediaItem`s to download and send on channel
+/// Pages handed to writers, from the oldest not done yet
+#[derive(Default)]
+struct Progress {
+    // Number of the page each item not done yet is on
+    pending: HashMap<String, u64>,
+    pages: VecDeque<Page>,
+    // Number of the oldest page and the next one to list
+    first: u64,
+    next: u64,
+    // Filename counters as of the last page done with
+    filenames: HashMap<String, u16>,
+}
+
+/// A page of items handed to writers
+struct Page {
+    pending: usize,
+    next_page_token: Option<String>,
+    // Filename counters this page changed (rather than all, which add up for large libraries)
+    filenames: Vec<(String, u16)>,
+}
+
+impl Checkpoint {
+    pub fn new(
+        target: &Path,
+        from_date: Option<NaiveDate>,
+        to_date: Option<NaiveDate>,
+        batch_size: i32,
+    ) -> Self {
+        // Page tokens are only valid for the very same list request
+        let filters = json!({
+            "from_date": from_date.map(|date| date.to_string()),
+            "to_date": to_date.map(|date| date.to_string()),
+            "page_size": batch_size,
+        });
+
+        Checkpoint {
+            path: target.join(CHECKPOINT_FILE),
+            filters,
+            progress: Mutex::new(Progress::default()),
+        }
+    }
+
+    /// Page token and filename counters to continue from, if any were saved
+    pub async fn load(&self) -> anyhow::Result<Option<(String, Filenames)>> {
+        let Ok(content) = fs::read_to_string(&self.path).await else {
+            return Ok(None);
+        };
+        let state: Value = serde_json::from_str(&content)?;
+
+        if state["filters"] != self.filters {
+            bail!(
+                "Filters changed since the interrupted pull, use the same dates or remove {:?}",
+                self.path
+            );
+        }
+
+        let Some(next_page_token) = state["next_page_token"].as_str() else {
+            return Ok(None);
+        };
+        // (Without the counters, names repeated later would lose their goo_N_ prefix.)
+        let mut counts = HashMap::new();
+        if let Some(counters) = state["filenames"].as_object() {
+            for (filename, count) in counters {
+                let Some(count) = count.as_u64().and_then(|count| u16::try_from(count).ok()) else {
+                    bail!("Invalid filename counter in {:?}", self.path);
+                };
+                counts.insert(filename.to_string(), count);
+            }
+        }
+        self.progress.lock().await.filenames = counts.clone();
+
+        Ok(Some((
+            next_page_token.to_string(),
+            Filenames {
+                counts,
+                changed: vec![],
+            },
+        )))
+    }
+
+    /// Track a page about to be handed to writers
+    pub async fn listed(
+        &self,
+        ids: impl Iterator<Item = &str>,
+        next_page_token: Option<&str>,
+        filenames: Vec<(String, u16)>,
+    ) -> anyhow::Result<()> {
+        let mut progress = self.progress.lock().await;
+        let number = progress.next;
+        progress.next += 1;
+
+        let mut pending = 0;
+        for id in ids {
+            progress.pending.insert(id.to_string(), number);
+            pending += 1;
+        }
+        progress.pages.push_back(Page {
+            pending,
+            next_page_token: next_page_token.map(str::to_string),
+            filenames,
+        });
+
+        self.advance(&mut progress).await
+    }
+
+    /// Count an item as done, once written (or failed for good)
+    pub async fn done(&self, id: &str) -> anyhow::Result<()> {
+        let mut progress = self.progress.lock().await;
+        let Some(number) = progress.pending.remove(id) else {
+            return Ok(());
+        };
+        let index = (number - progress.first) as usize;
+        progress.pages[index].pending -= 1;
+
+        self.advance(&mut progress).await
+    }
+
+    /// Move past pages done with, saving every few pages (or clearing after the last page)
+    async fn advance(&self, progress: &mut Progress) -> anyhow::Result<()> {
+        let mut due = None;
+        while progress.pages.front().is_some_and(|page| page.pending == 0) {
+            let Some(page) = progress.pages.pop_front() else {
+                break;
+            };
+            progress.first += 1;
+            progress.filenames.extend(page.filenames);
+            let Some(token) = page.next_page_token else {
+                return self.clear().await;
+            };
+            // (Always the last page done with, its counters are the ones collected.)
+            if due.is_some() || progress.first.is_multiple_of(CHECKPOINT_EVERY_PAGES) {
+                due = Some(token);
+            }
+        }
+
+        if let Some(token) = due {
+            self.save(&token, &progress.filenames).await?;
+            debug!("Listing checkpoint saved after {} pages", progress.first);
+        }
+
+        Ok(())
+    }
+
+    async fn save(
+        &self,
+        next_page_token: &str,
+        filenames: &HashMap<String, u16>,
+    ) -> anyhow::Result<()> {
+        let state = json!({
+            "filters": self.filters,
+            "next_page_token": next_page_token,
+            "filenames": filenames,
+        });
+
+        // Write aside and rename, so an interruption never leaves a torn file
+        let mut in_progress = self.path.clone().into_os_string();
+        in_progress.push(".tmp");
+        fs::write(&in_progress, state.to_string()).await?;
+        fs::rename(&in_progress, &self.path).await?;
+
+        Ok(())
+    }
+
+    async fn clear(&self) -> anyhow::Result<()> {
+        if fs::metadata(&self.path).await.is_ok() {
+            fs::remove_file(&self.path).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Refused items, counted per reason (large libraries would flood the log otherwise)
+#[derive(Default)]
+struct Refusals {
+    counts: HashMap<&'static str, u32>,
+}
+
+impl Refusals {
+    /// Log the first few examples per reason only
+    fn warn(&mut self, reason: &'static str, example: impl FnOnce() -> String) {
+        let count = self.counts.entry(reason).or_insert(0);
+        *count += 1;
+        if *count <= EXAMPLES_PER_REASON {
+            warn!("Refused to match {reason} {}", example());
+        } else if *count == EXAMPLES_PER_REASON + 1 {
+            warn!("Refused to match {reason} ... (more, counted in summary)");
+        }
+    }
+
+    fn summarize(&self) {
+        let mut counts: Vec<_> = self.counts.iter().collect();
+        counts.sort();
+        for (reason, count) in counts {
+            warn!("Refused {count} item(s) {reason}");
+        }
+    }
+}
+
+/// Collect attributes of `MediaItem`s to download and send on channel, counting them
 pub async fn select_media_and_send(
-    hub: PhotosLibrary<HttpsConnector<HttpConnector>>,
+    hub: PhotosLibrary<dns::Connector>,
     transmit_to_write: mpsc::Sender<MediaAttr>,
     from_date: Option<NaiveDate>,
     to_date: Option<NaiveDate>,
     batch_size: i32,
-) -> anyhow::Result<()> {
+    checkpoint: Option<&Checkpoint>,
+    resume_from: Option<(String, Filenames)>,
+) -> anyhow::Result<u64> {
     // Loop through Google Photos
-    let mut next_page_token: Option<String> = None;
-    let mut filenames: HashMap<String, u16> = HashMap::new();
+    let (mut next_page_token, mut filenames) = match resume_from {
+        Some((token, filenames)) => (Some(token), filenames),
+        None => (None, Filenames::default()),
+    };
+    let mut refusals = Refusals::default();
+    let mut pages: u32 = 0;
+    let mut listed: u64 = 0;
+    let mut blocked = Duration::ZERO;
 
     loop {
         // First list in batches
@@ -72,23 +337,55 @@ pub async fn select_media_and_send(
                         from_date,
                         to_date,
                         &mut filenames,
+                        &mut refusals,
                     );
 
                     next_page_token = token_returned;
+                    let changed = filenames.take_changed();
+                    if let Some(checkpoint) = checkpoint {
+                        checkpoint
+                            .listed(
+                                selection.iter().map(|item| item.id.as_str()),
+                                next_page_token.as_deref(),
+                                changed,
+                            )
+                            .await?;
+                    }
+                    let sending = Instant::now();
                     for item in selection {
-                        transmit_to_write.send(item.to_owned()).await?;
+                        if transmit_to_write.send(item.to_owned()).await.is_err() {
+                            // Writers stopped early, keep the checkpoint for continuing
+                            info!(
+                                "Writers take no more items, listing stopped after {pages} pages"
+                            );
+                            return Ok(listed);
+                        }
+                        listed += 1;
                     }
+                    blocked += sending.elapsed();
+
+                    // Back-pressure: how full is the queue to writers after this page?
+                    let depth = transmit_to_write.max_capacity() - transmit_to_write.capacity();
+                    debug!(
+                        "Queue depth {depth}/{}, sending page took {:.2?}",
+                        transmit_to_write.max_capacity(),
+                        sending.elapsed()
+                    );
+
+                    pages += 1;
                 }
             }
         }
 
         if next_page_token.is_none() {
+            info!("Listed {pages} pages, blocked on writers for {blocked:.2?}");
+            refusals.summarize();
             drop(transmit_to_write); // Close this end of the channel
             break;
         }
     }
 
-    Ok(())
+    Ok(listed)
 }
 
 /// Handle pattern matching and time windows
@@ -96,7 +393,8 @@ fn select_from_list(
     response: ListMediaItemsResponse,
     from_date: Option<NaiveDate>,
     to_date: Option<NaiveDate>,
-    filenames: &mut HashMap<String, u16>,
+    filenames: &mut Filenames,
+    refusals: &mut Refusals,
 ) -> (Option<String>, Vec<MediaAttr>) {
     let mut selection = Vec::<MediaAttr>::new();
     let mut next_page_token = response.next_page_token;
@@ -117,10 +415,10 @@ fn select_from_list(
                     contributor_info: _,
                     description: _,
                     filename: Some(filename),
-                    id: _,
+                    id: Some(id),
                     media_metadata: Some(metadata),
-                    mime_type: _,
-                    product_url: _,
+                    mime_type,
+                    product_url,
                 } => match metadata {
                     MediaMetadata {
                         creation_time: Some(creation_time),
@@ -136,62 +434,40 @@ fn select_from_list(
                                 && from_date.is_some_and(|from_date| creation_date >= from_date))
                             || (from_date.is_none() && to_date.is_none())
                         {
-                            match metadata {
+                            let dimensions = match metadata {
                                 MediaMetadata {
                                     creation_time: _,
                                     height: Some(height),
                                     photo: Some(_),
                                     video: None,
                                     width: Some(width),
-                                } => {
-                                    selected_dt += 1;
-
-                                    let unique_name: String;
-                                    if let Some(v) = filenames.get_mut(filename) {
-                                        *v += 1;
-                                        unique_name = format!("goo_{v}_{filename}");
-                                    } else {
-                                        filenames.insert(filename.to_string(), 0);
-                                        unique_name = filename.to_string();
-                                    }
-                                    selection.push(MediaAttr::ImageOrMotionPhotoBaseUrl(
-                                        url.to_string(),
-                                        unique_name,
-                                        width.to_owned(),
-                                        height.to_owned(),
-                                        creation_time.to_owned(),
-                                    ));
-                                }
+                                } => Some((width.to_owned(), height.to_owned())),
                                 MediaMetadata {
                                     creation_time: _,
                                     height: _,
                                     photo: None,
                                     video: Some(_),
                                     width: _,
-                                } => {
-                                    selected_dt += 1;
-
-                                    let unique_name: String;
-                                    if let Some(v) = filenames.get_mut(filename) {
-                                        *v += 1;
-                                        unique_name = format!("goo_{v}_{filename}");
-                                    } else {
-                                        filenames.insert(filename.to_string(), 0);
-                                        unique_name = filename.to_string();
-                                    }
-                                    selection.push(MediaAttr::VideoBaseUrl(
-                                        url.to_string(),
-                                        unique_name,
-                                        creation_time.to_owned(),
-                                    ));
-                                }
+                                } => None,
                                 _ => {
                                     unexpected += 1;
-                                    warn!(
-                                        "Refused to match without some photo or video {metadata:?}"
-                                    );
+                                    refusals.warn("without some photo or video", || {
+                                        format!("{metadata:?}")
+                                    });
+                                    return;
                                 }
-                            }
+                            };
+                            selected_dt += 1;
+
+                            selection.push(MediaAttr {
+                                id: id.to_string(),
+                                base_url: url.to_string(),
+                                product_url: product_url.clone().unwrap_or_default(),
+                                filename: filenames.unique(filename),
+                                mime: mime_type.clone().unwrap_or_default(),
+                                dimensions,
+                                creation_time: creation_time.to_owned(),
+                            });
                         } else {
                             skipped_dt += 1;
                             // End list early!
@@ -202,12 +478,14 @@ fn select_from_list(
                     }
                     _ => {
                         unexpected += 1;
-                        warn!("Refused to match without creation_time {metadata:?}");
+                        refusals.warn("without creation_time", || format!("{metadata:?}"));
                     }
                 },
                 _ => {
                     unexpected += 1;
-                    warn!("Refused to match without all of: base_url, filename, metadata {item:?}");
+                    refusals.warn("without all of: base_url, filename, id, metadata", || {
+                        format!("{item:?}")
+                    });
                 }
             }
         });
@@ -218,3 +496,119 @@ fn select_from_list(
 
     (next_page_token, selection)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checkpoint(name: &str) -> Checkpoint {
+        let target = std::env::temp_dir().join(format!("goopho-{}-{name}", std::process::id()));
+        std::fs::create_dir_all(&target).unwrap();
+        let _ = std::fs::remove_file(target.join(CHECKPOINT_FILE));
+        Checkpoint::new(&target, None, None, 50)
+    }
+
+    /// List pages of one item each ("0", "1", ...), the last one with no next page
+    async fn list_pages(checkpoint: &Checkpoint, pages: u64, last: bool) {
+        for page in 0..pages {
+            let token = format!("token{}", page + 1);
+            let next_page_token = (!last || page + 1 < pages).then_some(token.as_str());
+            let id = page.to_string();
+            checkpoint
+                .listed(std::iter::once(id.as_str()), next_page_token, vec![])
+                .await
+                .unwrap();
+        }
+    }
+
+    async fn saved_token(checkpoint: &Checkpoint) -> Option<String> {
+        checkpoint.load().await.unwrap().map(|(token, _)| token)
+    }
+
+    #[tokio::test]
+    async fn saves_once_oldest_pages_are_done() {
+        let checkpoint = checkpoint("out-of-order");
+        list_pages(&checkpoint, CHECKPOINT_EVERY_PAGES, false).await;
+
+        for page in (1..CHECKPOINT_EVERY_PAGES).rev() {
+            checkpoint.done(&page.to_string()).await.unwrap();
+            assert_eq!(saved_token(&checkpoint).await, None);
+        }
+        checkpoint.done("0").await.unwrap();
+        assert_eq!(
+            saved_token(&checkpoint).await,
+            Some(format!("token{CHECKPOINT_EVERY_PAGES}"))
+        );
+    }
+
+    #[tokio::test]
+    async fn item_not_done_holds_checkpoint() {
+        let checkpoint = checkpoint("held");
+        list_pages(&checkpoint, 2 * CHECKPOINT_EVERY_PAGES, false).await;
+
+        // Page 3 is left, as with an item stopped by the retry budget
+        for page in (0..2 * CHECKPOINT_EVERY_PAGES).filter(|page| *page != 3) {
+            checkpoint.done(&page.to_string()).await.unwrap();
+        }
+        assert_eq!(saved_token(&checkpoint).await, None);
+
+        checkpoint.done("3").await.unwrap();
+        assert_eq!(
+            saved_token(&checkpoint).await,
+            Some(format!("token{}", 2 * CHECKPOINT_EVERY_PAGES))
+        );
+    }
+
+    #[tokio::test]
+    async fn last_page_clears() {
+        let checkpoint = checkpoint("last-page");
+        list_pages(&checkpoint, CHECKPOINT_EVERY_PAGES + 1, true).await;
+
+        for page in 0..CHECKPOINT_EVERY_PAGES {
+            checkpoint.done(&page.to_string()).await.unwrap();
+        }
+        assert!(checkpoint.path.exists());
+
+        checkpoint
+            .done(&CHECKPOINT_EVERY_PAGES.to_string())
+            .await
+            .unwrap();
+        assert!(!checkpoint.path.exists());
+    }
+
+    #[tokio::test]
+    async fn filename_counters_carry_over() {
+        let checkpoint = checkpoint("filenames");
+        let mut filenames = Filenames::default();
+
+        for page in 0..CHECKPOINT_EVERY_PAGES {
+            assert_eq!(filenames.unique("IMG.jpg"), unique_after(page, "IMG.jpg"));
+            let id = page.to_string();
+            checkpoint
+                .listed(
+                    std::iter::once(id.as_str()),
+                    Some("token"),
+                    filenames.take_changed(),
+                )
+                .await
+                .unwrap();
+            checkpoint.done(&id).await.unwrap();
+        }
+
+        let Some((_, mut filenames)) = checkpoint.load().await.unwrap() else {
+            panic!("checkpoint not saved");
+        };
+        assert_eq!(
+            filenames.unique("IMG.jpg"),
+            unique_after(CHECKPOINT_EVERY_PAGES, "IMG.jpg")
+        );
+        assert_eq!(filenames.unique("other.jpg"), "other.jpg");
+    }
+
+    fn unique_after(seen: u64, filename: &str) -> String {
+        match seen {
+            0 => filename.to_string(),
+            _ => format!("goo_{seen}_{filename}"),
+        }
+    }
+}
@@ -1,125 +1,305 @@
 //! A command line app to download images and videos from Google Photos
 
-use anyhow::{bail, Context};
-use google_photoslibrary1 as photoslibrary1;
-use photoslibrary1::{hyper, hyper_rustls, oauth2, PhotosLibrary};
-use tokio::{fs, sync::mpsc};
+use std::{
+    io::{self, IsTerminal},
+    sync::Arc,
+    time::Instant,
+};
+
+use tokio::{fs, sync::mpsc, task::JoinHandle};
 use tracing::{debug, info};
 use tracing_indicatif::IndicatifLayer;
-use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+use tracing_subscriber::{
+    fmt::{self, writer::BoxMakeWriter},
+    layer::SubscriberExt,
+    util::SubscriberInitExt,
+};
 
 mod acc;
+mod auth;
+mod completions;
 mod config;
+mod dns;
+mod doctor;
 mod download;
+mod dryrun;
 mod hub;
+mod plan;
+mod targets;
+mod throttle;
 
 const BATCH_SIZE: i32 = 50;
 const QUEUE_DEPTH: usize = 10;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    // Subscribe to traces and progress indicator
+    // Get command line args
+    let args: config::Cmdlargs = argh::from_env();
 
-    let indicatif_layer = IndicatifLayer::new();
+    // Subscribe to traces and progress indicator
+    //  (No progress bars for pipes and files, they'd only garble the output.)
+    let indicatif_layer =
+        (!args.no_progress && io::stderr().is_terminal()).then(IndicatifLayer::new);
+    let writer = match indicatif_layer.as_ref() {
+        Some(layer) => BoxMakeWriter::new(layer.get_stderr_writer()),
+        None => BoxMakeWriter::new(io::stderr),
+    };
     tracing_subscriber::registry()
-        .with(fmt::layer().with_writer(indicatif_layer.get_stderr_writer()))
-        .with(EnvFilter::from_default_env()) // Use RUST_LOG env var
+        .with(fmt::layer().with_writer(writer))
+        .with(args.log_filter()?) // Flags, else RUST_LOG env var, else info
         .with(indicatif_layer)
         .init();
 
     // console_subscriber::init();
 
-    // Get command line args
-    let args: config::Cmdlargs = argh::from_env();
     debug!("{args:?}");
 
-    // Path to token store
-    let store = config::get_token_store_path()?
-        .to_str()
-        .context("Invalid char in path to token store")?
-        .to_string();
-
-    // Get app secret
-    //  (It's simply a design choice here to make this mandatory. Apps like `rclone`
-    //  store this to a config file.)
-    let app_secret = config::get_app_secret(args.client_secret).await?;
-
-    // Client setup and auth
-    let client = hyper::Client::builder().build(
-        hyper_rustls::HttpsConnectorBuilder::new()
-            .with_native_roots()
-            .https_only()
-            .enable_http1()
-            .enable_http2()
-            .build(),
-    );
-    let auth = oauth2::InstalledFlowAuthenticator::builder(
-        app_secret,
-        oauth2::InstalledFlowReturnMethod::HTTPRedirect,
-    )
-    .hyper_client(client.clone())
-    .persist_tokens_to_disk(&store)
-    .build()
-    .await?;
-    info!("Tokens stored to '{store}'");
+    match args.command {
+        config::Command::Pull(pull_args) => pull(pull_args, args.quiet).await,
+        config::Command::Plan(plan_args) => plan(plan_args).await,
+        config::Command::Apply(apply_args) => apply(apply_args, args.quiet).await,
+        config::Command::Doctor(doctor_args) => doctor::run(doctor_args).await,
+        config::Command::Completions(completions_args) => {
+            print!("{}", completions::script(completions_args.shell));
+            Ok(())
+        }
+    }
+}
+
+/// Select media files and download them
+async fn pull(args: config::PullArgs, machine_summary: bool) -> anyhow::Result<()> {
+    let started = Instant::now();
+    args.validate()?;
+    let headers = args.headers();
+
+    // Output of dry-run listing, failing early rather than after auth
+    //  (A listing on stdout leaves no room for the machine summary.)
+    let listing_to_stdout = args.dry_run && args.output.is_none();
+    let sink = if args.dry_run {
+        Some(dryrun::open(args.output).await?)
+    } else {
+        None
+    };
 
     // Ready for the real thing
-    let hub = PhotosLibrary::new(client.clone(), auth);
+    let resolver = dns::Resolver::new(&args.resolve, args.ip_version);
+    let (client, hub) = auth::connect(args.client_secret, args.auth, resolver).await?;
 
-    // See about the target directory
+    // Create the target directories
+    //  (Validation made sure they do not exist yet, unless continuing.)
     //  TODO: Only run this code before actually writing.
-    if fs::metadata(&args.target).await.is_ok() {
-        bail!("Target dir exists");
-    } else if !args.dry_run {
-        fs::create_dir(&args.target).await?;
+    let targets = Arc::new(targets::Targets::new(
+        args.target.clone(),
+        args.capacity,
+        args.spill,
+    ));
+    if !args.dry_run {
+        for dir in targets.dirs() {
+            if fs::metadata(dir).await.is_err() {
+                fs::create_dir(dir).await?;
+            }
+        }
+    }
+    if args.resume {
+        targets.count_existing().await?;
+    }
+
+    // Where listing left off (dry runs have no target to keep state in)
+    //  (Writers move it on, once all items of a page are on disk.)
+    let checkpoint = (!args.dry_run).then(|| {
+        Arc::new(hub::Checkpoint::new(
+            &args.target,
+            args.from_date,
+            args.to_date,
+            BATCH_SIZE,
+        ))
+    });
+    let resume_from = match checkpoint.as_ref() {
+        Some(checkpoint) if args.resume => checkpoint.load().await?,
+        _ => None,
+    };
+    if resume_from.is_some() {
+        info!("Continuing listing from saved checkpoint");
     }
 
     // Setup for accounting
     let (track_and_log, events) = mpsc::channel::<acc::Event>(128);
-    let accountant = acc::track_events(events).await;
+    let accountant = acc::track_events(events, args.verified_list, args.manifest).await?;
+
+    // Setup for dry-run listing
+    let (dry_run, listing) = if let Some(sink) = sink {
+        let (transmit_rows, rows) = mpsc::channel::<dryrun::Row>(QUEUE_DEPTH);
+        let listing = dryrun::write_rows(rows, args.columns, sink).await;
+        (Some(transmit_rows), Some(listing))
+    } else {
+        (None, None)
+    };
 
     // Channel to writers
     let (transmit_to_write, write_request) = mpsc::channel::<hub::MediaAttr>(QUEUE_DEPTH);
+    let quota = download::Quota::new(args.stop_after_files, args.stop_after_bytes);
 
     // Set up the channel's receiving side for downloads and disk writes
     //  (Manages its own join handles internally)
+    let ctx = download::WriteContext {
+        headers,
+        write_buffer: args.write_buffer * 1024,
+        sha256: args.sha256,
+        retry_budget: args.retry_budget.map(download::RetryBudget::new),
+        quota: quota.clone(),
+        skip_existing: args.resume,
+        checkpoint: checkpoint.clone(),
+        client,
+        track_and_log: track_and_log.clone(),
+        targets: targets.clone(),
+        dry_run,
+        throttle: throttle::Throttle::new(args.concurrency, args.adaptive),
+    };
     let writer = if args.unordered {
-        download::photos_to_disk_unordered(
-            write_request,
-            track_and_log.clone(),
-            args.target,
-            client,
-            args.dry_run,
-        )
-        .await
+        download::photos_to_disk_unordered(write_request, ctx).await
     } else {
-        download::photos_to_disk(
-            write_request,
-            track_and_log.clone(),
-            args.target,
-            client,
-            args.dry_run,
-        )
-        .await
+        download::photos_to_disk(write_request, ctx).await
     };
 
     // Start selecting media files to download
-    hub::select_media_and_send(
+    let listed = hub::select_media_and_send(
         hub,
         transmit_to_write,
         args.from_date,
         args.to_date,
         BATCH_SIZE,
+        checkpoint.as_deref(),
+        resume_from,
     )
-    .await?;
+    .await;
 
     // Be patient, don't quit
     //  (?? is for propagating outer as well as inner results)
-    writer.await??;
+    //  (Writers giving up close the channel, so their error goes first.)
+    //  (Summarizing flushes records before any error is returned.)
+    let written = writer.await?;
+    let report = summarize(&track_and_log, accountant).await?;
+    written?;
+    let listed = listed?;
+    if let Some(listing) = listing {
+        listing.await??;
+    }
+    if quota.reached() {
+        // (The checkpoint stays before items skipped at the quota.)
+        info!("Stopped at quota, add --continue to pull the rest");
+    }
+    if targets.full() {
+        info!("Stopped with all targets full, add --continue and another --spill for the rest");
+    }
 
-    // Ask for summary
-    track_and_log.send(acc::Event::Summarize).await?;
-    accountant.await?;
+    let report = acc::PullReport {
+        listed,
+        duration: started.elapsed(),
+        ..report
+    };
+    if machine_summary && !listing_to_stdout {
+        println!("{}", report.machine_summary());
+    }
+
+    Ok(())
+}
+
+/// Select media files into a plan file, to review before applying it
+async fn plan(args: config::PlanArgs) -> anyhow::Result<()> {
+    args.validate()?;
+
+    let resolver = dns::Resolver::new(&args.resolve, args.ip_version);
+    let (_, hub) = auth::connect(args.client_secret, args.auth, resolver).await?;
+
+    let (transmit_to_plan, items) = mpsc::channel::<hub::MediaAttr>(QUEUE_DEPTH);
+    let planner = plan::write_plan(items, args.target, args.output).await;
+
+    let listed = hub::select_media_and_send(
+        hub,
+        transmit_to_plan,
+        args.from_date,
+        args.to_date,
+        BATCH_SIZE,
+        None,
+        None,
+    )
+    .await;
+
+    planner.await??;
+    listed?;
 
     Ok(())
 }
+
+/// Download what a plan lists, skipping files already written
+async fn apply(args: config::ApplyArgs, machine_summary: bool) -> anyhow::Result<()> {
+    let started = Instant::now();
+    args.validate()?;
+    let headers = args.headers();
+    let plan = plan::Plan::load(&args.plan).await?;
+
+    let resolver = dns::Resolver::new(&args.resolve, args.ip_version);
+    let (client, hub) = auth::connect(args.client_secret, args.auth, resolver).await?;
+
+    if fs::metadata(&plan.target).await.is_err() {
+        fs::create_dir(&plan.target).await?;
+    }
+    let targets = targets::Targets::new(plan.target.clone(), None, vec![]);
+    let items = plan.pending();
+
+    // Setup for accounting
+    let (track_and_log, events) = mpsc::channel::<acc::Event>(128);
+    let accountant = acc::track_events(events, args.verified_list, args.manifest).await?;
+
+    // Channel to writers
+    let (transmit_to_write, write_request) = mpsc::channel::<hub::MediaAttr>(QUEUE_DEPTH);
+    let quota = download::Quota::new(args.stop_after_files, args.stop_after_bytes);
+    let ctx = download::WriteContext {
+        headers,
+        write_buffer: args.write_buffer * 1024,
+        sha256: args.sha256,
+        retry_budget: args.retry_budget.map(download::RetryBudget::new),
+        quota: quota.clone(),
+        skip_existing: false,
+        checkpoint: None,
+        client,
+        track_and_log: track_and_log.clone(),
+        targets: Arc::new(targets),
+        dry_run: None,
+        throttle: throttle::Throttle::new(args.concurrency, args.adaptive),
+    };
+    let writer = download::photos_to_disk(write_request, ctx).await;
+
+    // Base URLs expire, so the plan only keeps IDs and gets fresh ones
+    let sent = plan::refresh_and_send(hub, items, transmit_to_write, BATCH_SIZE as usize).await;
+
+    // (Writers giving up close the channel, so their error goes first.)
+    let written = writer.await?;
+    let report = summarize(&track_and_log, accountant).await?;
+    written?;
+    let listed = sent?;
+    if quota.reached() {
+        info!("Stopped at quota, apply the plan again to download the rest");
+    }
+
+    let report = acc::PullReport {
+        listed,
+        duration: started.elapsed(),
+        ..report
+    };
+    if machine_summary {
+        println!("{}", report.machine_summary());
+    }
+
+    Ok(())
+}
+
+/// Ask for summary, once the accountant flushed what it recorded
+async fn summarize(
+    track_and_log: &mpsc::Sender<acc::Event>,
+    accountant: JoinHandle<acc::PullReport>,
+) -> anyhow::Result<acc::PullReport> {
+    track_and_log.send(acc::Event::Summarize).await?;
+
+    Ok(accountant.await?)
+}
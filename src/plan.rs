@@ -0,0 +1,241 @@
+//! Plan/apply - review (and trim) what a pull would write before downloading
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context};
+use google_photoslibrary1 as photoslibrary1;
+use photoslibrary1::{
+    chrono::{DateTime, Utc},
+    PhotosLibrary,
+};
+use serde_json::{json, Value};
+use tokio::{fs, sync::mpsc, task::JoinHandle};
+use tracing::{info, warn};
+
+use crate::{dns, hub::MediaAttr};
+
+/// One media item to download, as written to (and edited in) the plan file
+pub struct Planned {
+    id: String,
+    product_url: String,
+    filename: String,
+    mime: String,
+    // Photos only
+    dimensions: Option<(i64, i64)>,
+    creation_time: DateTime<Utc>,
+}
+
+impl Planned {
+    /// Keep everything but the base URL, which expires long before a multi-day run ends
+    fn from_attr(item: MediaAttr) -> Self {
+        Planned {
+            id: item.id,
+            product_url: item.product_url,
+            filename: item.filename,
+            mime: item.mime,
+            dimensions: item.dimensions,
+            creation_time: item.creation_time,
+        }
+    }
+
+    /// Back to what writers expect, with a fresh base URL
+    fn into_attr(self, base_url: String) -> MediaAttr {
+        MediaAttr {
+            id: self.id,
+            base_url,
+            product_url: self.product_url,
+            filename: self.filename,
+            mime: self.mime,
+            dimensions: self.dimensions,
+            creation_time: self.creation_time,
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "id": self.id,
+            "filename": self.filename,
+            "kind": if self.dimensions.is_some() { "photo" } else { "video" },
+            "mime": self.mime,
+            "width": self.dimensions.map(|(w, _)| w),
+            "height": self.dimensions.map(|(_, h)| h),
+            "creation_time": self.creation_time.to_rfc3339(),
+            "product_url": self.product_url,
+        })
+    }
+
+    fn from_json(item: &Value) -> anyhow::Result<Self> {
+        let field = |name: &str| {
+            item[name]
+                .as_str()
+                .map(str::to_string)
+                .with_context(|| format!("Planned item without {name}: {item}"))
+        };
+        let dimensions = match field("kind")?.as_str() {
+            "photo" => Some((
+                item["width"]
+                    .as_i64()
+                    .with_context(|| format!("Planned photo without width: {item}"))?,
+                item["height"]
+                    .as_i64()
+                    .with_context(|| format!("Planned photo without height: {item}"))?,
+            )),
+            "video" => None,
+            other => bail!("Unknown kind '{other}' in plan, expected photo or video"),
+        };
+
+        Ok(Planned {
+            id: field("id")?,
+            product_url: field("product_url")?,
+            filename: field("filename")?,
+            mime: field("mime")?,
+            dimensions,
+            creation_time: DateTime::parse_from_rfc3339(&field("creation_time")?)?
+                .with_timezone(&Utc),
+        })
+    }
+}
+
+/// Items to download and where to
+pub struct Plan {
+    pub target: PathBuf,
+    items: Vec<Planned>,
+}
+
+impl Plan {
+    /// Read a (possibly edited) plan file, rejecting edits writers could not follow
+    pub async fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = fs::read_to_string(path)
+            .await
+            .with_context(|| format!("Failed to read plan {path:?}"))?;
+        let plan: Value = serde_json::from_str(&content)?;
+
+        let target = plan["target"]
+            .as_str()
+            .map(PathBuf::from)
+            .context("Plan without target folder")?;
+        let items = plan["items"]
+            .as_array()
+            .context("Plan without items")?
+            .iter()
+            .map(Planned::from_json)
+            .collect::<anyhow::Result<Vec<Planned>>>()?;
+
+        let mut filenames = HashSet::new();
+        for item in &items {
+            if Path::new(&item.filename).file_name() != Some(item.filename.as_ref()) {
+                bail!(
+                    "Planned filename '{}' is not a plain file name",
+                    item.filename
+                );
+            }
+            if !filenames.insert(&item.filename) {
+                bail!("Filename '{}' is planned more than once", item.filename);
+            }
+        }
+
+        Ok(Plan { target, items })
+    }
+
+    /// Drop items already written by an earlier (interrupted) apply
+    pub fn pending(self) -> Vec<Planned> {
+        let total = self.items.len();
+        let pending: Vec<Planned> = self
+            .items
+            .into_iter()
+            .filter(|item| !self.target.join(&item.filename).exists())
+            .collect();
+        if pending.len() < total {
+            info!(
+                "Skipping {} item(s) already written to {:?}",
+                total - pending.len(),
+                self.target
+            );
+        }
+
+        pending
+    }
+}
+
+/// Collect selected items and write them to a plan file, once listing is done
+pub async fn write_plan(
+    mut items: mpsc::Receiver<MediaAttr>,
+    target: PathBuf,
+    output: PathBuf,
+) -> JoinHandle<anyhow::Result<()>> {
+    tokio::spawn(async move {
+        let mut planned = vec![];
+        while let Some(item) = items.recv().await {
+            planned.push(Planned::from_attr(item).to_json());
+        }
+
+        let count = planned.len();
+        // (Validation made sure the target is UTF-8.)
+        let plan = json!({
+            "target": target.to_string_lossy(),
+            "items": planned,
+        });
+        fs::write(&output, serde_json::to_string_pretty(&plan)?).await?;
+        info!("Planned {count} item(s) into {target:?}, review {output:?} before applying it");
+
+        Ok(())
+    })
+}
+
+/// Get fresh base URLs for planned items and send them on to writers, counting them
+pub async fn refresh_and_send(
+    hub: PhotosLibrary<dns::Connector>,
+    items: Vec<Planned>,
+    transmit_to_write: mpsc::Sender<MediaAttr>,
+    batch_size: usize,
+) -> anyhow::Result<u64> {
+    let mut listed: u64 = 0;
+    let mut items = items.into_iter().peekable();
+    while items.peek().is_some() {
+        let batch: Vec<Planned> = items.by_ref().take(batch_size).collect();
+
+        let call = batch
+            .iter()
+            .fold(hub.media_items().batch_get(), |call, item| {
+                call.add_media_item_ids(&item.id)
+            });
+        let (_, response) = call.doit().await?;
+
+        let mut urls = HashMap::new();
+        for result in response.media_item_results.unwrap_or_default() {
+            match result.media_item {
+                Some(media_item) => {
+                    if let (Some(id), Some(url)) = (media_item.id, media_item.base_url) {
+                        urls.insert(id, url);
+                    }
+                }
+                None => {
+                    let message = result.status.and_then(|status| status.message);
+                    warn!(
+                        "Planned item not available: {}",
+                        message.unwrap_or_default()
+                    );
+                }
+            }
+        }
+
+        for item in batch {
+            match urls.remove(&item.id) {
+                Some(url) => {
+                    if transmit_to_write.send(item.into_attr(url)).await.is_err() {
+                        info!("Writers take no more items, stopping");
+                        return Ok(listed);
+                    }
+                    listed += 1;
+                }
+                None => warn!("Skipping {} ({}), no base URL", item.filename, item.id),
+            }
+        }
+    }
+
+    drop(transmit_to_write); // Close this end of the channel
+    Ok(listed)
+}
@@ -0,0 +1,213 @@
+//! Targets - folders to write to, spilling over to the next when one is full
+
+use std::{
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use tokio::fs;
+
+/// Room to reserve for downloads of unknown size (sent without Content-Length)
+///  (Capped at a target's capacity, so small ones can still take them.)
+const UNSIZED_ESTIMATE: u64 = 1 << 30;
+
+/// Folder with an optional capacity (counting what is in it)
+struct Target {
+    dir: PathBuf,
+    capacity: Option<u64>,
+    used: AtomicU64,
+}
+
+/// Room taken in one target, until the download is settled
+pub struct Reservation {
+    index: usize,
+    size: u64,
+    pub dir: PathBuf,
+}
+
+/// Target folders in the order they fill up
+pub struct Targets {
+    targets: Vec<Target>,
+    // Once a download found no room, the run takes no more items
+    full: AtomicBool,
+}
+
+impl Targets {
+    pub fn new(first: PathBuf, capacity: Option<u64>, spill: Vec<(PathBuf, u64)>) -> Self {
+        let targets = std::iter::once((first, capacity))
+            .chain(
+                spill
+                    .into_iter()
+                    .map(|(dir, capacity)| (dir, Some(capacity))),
+            )
+            .map(|(dir, capacity)| Target {
+                dir,
+                capacity,
+                used: AtomicU64::new(0),
+            })
+            .collect();
+
+        Targets {
+            targets,
+            full: AtomicBool::new(false),
+        }
+    }
+
+    /// Folder for state and listings
+    pub fn first(&self) -> &Path {
+        &self.targets[0].dir
+    }
+
+    pub fn dirs(&self) -> impl Iterator<Item = &Path> {
+        self.targets.iter().map(|target| target.dir.as_path())
+    }
+
+    /// Count what earlier (interrupted) runs left in the targets against their capacity
+    pub async fn count_existing(&self) -> anyhow::Result<()> {
+        for target in &self.targets {
+            let Ok(mut entries) = fs::read_dir(&target.dir).await else {
+                continue;
+            };
+            let mut used = 0;
+            while let Some(entry) = entries.next_entry().await? {
+                let metadata = entry.metadata().await?;
+                if metadata.is_file() {
+                    used += metadata.len();
+                }
+            }
+            target.used.store(used, Ordering::Relaxed);
+        }
+
+        Ok(())
+    }
+
+    /// Whether a file was written to any of the targets before
+    pub async fn contain(&self, filename: &str) -> bool {
+        for dir in self.dirs() {
+            if fs::metadata(dir.join(filename)).await.is_ok() {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Whether a download found no target with room for it
+    pub fn full(&self) -> bool {
+        self.full.load(Ordering::Relaxed)
+    }
+
+    /// Room for `size` bytes in the first target that has it, else the targets are full
+    ///  (Without a size, a generous estimate, settled once written.)
+    pub fn reserve(&self, size: Option<u64>) -> Option<Reservation> {
+        let reservation = self.targets.iter().enumerate().find_map(|(index, target)| {
+            let need = size.unwrap_or_else(|| {
+                target
+                    .capacity
+                    .map_or(UNSIZED_ESTIMATE, |capacity| capacity.min(UNSIZED_ESTIMATE))
+            });
+            target
+                .used
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                    target
+                        .capacity
+                        .is_none_or(|capacity| used.saturating_add(need) <= capacity)
+                        .then_some(used.saturating_add(need))
+                })
+                .ok()
+                .map(|_| Reservation {
+                    index,
+                    size: need,
+                    dir: target.dir.clone(),
+                })
+        });
+        if reservation.is_none() {
+            self.full.store(true, Ordering::Relaxed);
+        }
+
+        reservation
+    }
+
+    /// Replace the reservation by what was actually written
+    pub fn settle(&self, reservation: Reservation, written: u64) {
+        let used = &self.targets[reservation.index].used;
+        used.fetch_add(written, Ordering::Relaxed);
+        used.fetch_sub(reservation.size, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn targets(capacity: Option<u64>, spill: &[u64]) -> Targets {
+        Targets::new(
+            PathBuf::from("first"),
+            capacity,
+            spill
+                .iter()
+                .enumerate()
+                .map(|(index, capacity)| (PathBuf::from(format!("spill{index}")), *capacity))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn spills_to_the_next_target() {
+        let targets = targets(Some(100), &[1000]);
+
+        let first = targets.reserve(Some(60)).unwrap();
+        assert_eq!(first.dir, PathBuf::from("first"));
+        let spilled = targets.reserve(Some(60)).unwrap();
+        assert_eq!(spilled.dir, PathBuf::from("spill0"));
+        // Smaller files still fill the room left in the first target
+        assert_eq!(
+            targets.reserve(Some(40)).unwrap().dir,
+            PathBuf::from("first")
+        );
+        assert!(!targets.full());
+    }
+
+    #[test]
+    fn full_once_nothing_fits() {
+        let targets = targets(Some(100), &[100]);
+        targets.reserve(Some(100)).unwrap();
+        targets.reserve(Some(100)).unwrap();
+
+        assert!(targets.reserve(Some(1)).is_none());
+        assert!(targets.full());
+    }
+
+    #[test]
+    fn settle_replaces_reservation() {
+        let targets = targets(Some(100), &[]);
+
+        let reservation = targets.reserve(Some(80)).unwrap();
+        targets.settle(reservation, 30);
+        assert!(targets.reserve(Some(70)).is_some());
+        assert!(targets.reserve(Some(1)).is_none());
+    }
+
+    #[test]
+    fn unsized_estimate_capped_at_capacity() {
+        let targets = targets(Some(100), &[UNSIZED_ESTIMATE * 2]);
+
+        let small = targets.reserve(None).unwrap();
+        assert_eq!((small.dir.as_path(), small.size), (Path::new("first"), 100));
+        let large = targets.reserve(None).unwrap();
+        assert_eq!(large.size, UNSIZED_ESTIMATE);
+        // (Once settled, the estimate no longer fits in what is left of the small one.)
+        targets.settle(small, 10);
+        assert_eq!(targets.reserve(None).unwrap().dir, PathBuf::from("spill0"));
+        targets.settle(large, 10);
+    }
+
+    #[test]
+    fn without_capacity_never_full() {
+        let targets = targets(None, &[]);
+        for _ in 0..10 {
+            assert!(targets.reserve(None).is_some());
+        }
+        assert!(targets.reserve(Some(u64::MAX)).is_some());
+        assert!(!targets.full());
+    }
+}
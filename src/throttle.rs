@@ -0,0 +1,175 @@
+//! Throttle - bound download concurrency, optionally adapting it (AIMD)
+
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::debug;
+
+/// Concurrency to start with when adapting
+const ADAPTIVE_START: usize = 4;
+
+/// Limit on concurrent downloads
+pub struct Throttle {
+    permits: Arc<Semaphore>,
+    max: usize,
+    adaptive: bool,
+    state: Mutex<State>,
+}
+
+/// Internal details
+struct State {
+    limit: usize,
+    successes: usize,
+    // Permits to retire when handed back (after decreasing the limit)
+    debt: usize,
+}
+
+/// Held while downloading
+pub struct Permit {
+    permit: Option<OwnedSemaphorePermit>,
+    throttle: Arc<Throttle>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut state = self.throttle.state.lock().unwrap();
+        if state.debt > 0 {
+            state.debt -= 1;
+            if let Some(permit) = self.permit.take() {
+                permit.forget();
+            }
+        }
+    }
+}
+
+impl Throttle {
+    /// Static limit of `max`, or start low and adapt up to `max`
+    pub fn new(max: usize, adaptive: bool) -> Arc<Self> {
+        let limit = if adaptive {
+            ADAPTIVE_START.min(max)
+        } else {
+            max
+        };
+
+        Arc::new(Throttle {
+            permits: Arc::new(Semaphore::new(limit)),
+            max,
+            adaptive,
+            state: Mutex::new(State {
+                limit,
+                successes: 0,
+                debt: 0,
+            }),
+        })
+    }
+
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    /// Wait for a free slot (FIFO, so ordered processing stays ordered)
+    pub async fn acquire(self: &Arc<Self>) -> anyhow::Result<Permit> {
+        let permit = self.permits.clone().acquire_owned().await?;
+
+        Ok(Permit {
+            permit: Some(permit),
+            throttle: self.clone(),
+        })
+    }
+
+    /// Additive increase, one more slot per window of successful downloads
+    pub fn success(&self) {
+        if !self.adaptive {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.successes += 1;
+        if state.successes >= state.limit && state.limit < self.max {
+            state.successes = 0;
+            state.limit += 1;
+            if state.debt > 0 {
+                state.debt -= 1;
+            } else {
+                self.permits.add_permits(1);
+            }
+            debug!("Concurrency raised to {}", state.limit);
+        }
+    }
+
+    /// Multiplicative decrease on timeouts or when throttled by the server
+    pub fn congestion(&self) {
+        if !self.adaptive {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let limit = (state.limit / 2).max(1);
+        state.debt += state.limit - limit;
+        state.limit = limit;
+        state.successes = 0;
+        debug!("Concurrency lowered to {limit}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit(throttle: &Throttle) -> usize {
+        throttle.state.lock().unwrap().limit
+    }
+
+    #[tokio::test]
+    async fn static_limit_ignores_feedback() {
+        let throttle = Throttle::new(8, false);
+        throttle.congestion();
+        for _ in 0..100 {
+            throttle.success();
+        }
+
+        assert_eq!(limit(&throttle), 8);
+        assert_eq!(throttle.permits.available_permits(), 8);
+    }
+
+    #[tokio::test]
+    async fn adaptive_increases_up_to_max() {
+        let throttle = Throttle::new(6, true);
+        assert_eq!(limit(&throttle), ADAPTIVE_START);
+
+        // One more slot after a window of as many successes as the limit
+        for _ in 0..ADAPTIVE_START {
+            throttle.success();
+        }
+        assert_eq!(limit(&throttle), ADAPTIVE_START + 1);
+        assert_eq!(throttle.permits.available_permits(), ADAPTIVE_START + 1);
+
+        for _ in 0..100 {
+            throttle.success();
+        }
+        assert_eq!(limit(&throttle), 6);
+        assert_eq!(throttle.permits.available_permits(), 6);
+    }
+
+    #[tokio::test]
+    async fn adaptive_halves_on_congestion() {
+        let throttle = Throttle::new(8, true);
+        let permits = [
+            throttle.acquire().await.unwrap(),
+            throttle.acquire().await.unwrap(),
+            throttle.acquire().await.unwrap(),
+        ];
+
+        throttle.congestion();
+        assert_eq!(limit(&throttle), 2);
+        // Slots in use are retired as they are handed back
+        drop(permits);
+        assert_eq!(throttle.permits.available_permits(), 2);
+
+        throttle.congestion();
+        throttle.congestion();
+        assert_eq!(limit(&throttle), 1);
+        drop(throttle.acquire().await.unwrap());
+        assert_eq!(throttle.permits.available_permits(), 1);
+    }
+}